@@ -2,10 +2,10 @@ use std::{
     borrow::Borrow,
     collections::{HashMap, VecDeque},
     os::fd::{AsRawFd, BorrowedFd},
+    path::PathBuf,
     time::Duration,
 };
 
-use binrw::BinRead;
 use futures::{
     future::{join, ready},
     stream::FuturesUnordered,
@@ -15,95 +15,290 @@ use nalgebra::Vector3;
 
 use magic_loc_central::*;
 
-use stream_decoder::MagicLocStreamDecoder;
+use serde::{Deserialize, Serialize};
+use stream_decoder::{MagicLocPacketDecoder, ReceiveTime, Received, TimestampedDecoder};
+use stream_encoder::MagicLocStreamEncoder;
 use tmq::{self, Context};
-use tokio_serial::{self, SerialPort, SerialPortBuilderExt, SerialStream};
-use tokio_util::codec::Decoder;
-use tracing::{debug, error, info, trace};
+use tokio::{
+    io::WriteHalf,
+    sync::{broadcast, mpsc},
+};
+use tokio_serial::SerialStream;
+use tokio_util::codec::FramedWrite;
+use tracing::{debug, error, info, trace, warn};
+
+/// Time to wait for the firmware to answer a `GetVersion` command
+const VERSION_TIMEOUT: Duration = Duration::from_secs(1);
+
+type SerialWriter = FramedWrite<WriteHalf<SerialStream>, MagicLocStreamEncoder>;
+
+/// Message with the common host time of its device timestamp, and its receive time
+#[derive(Debug, Clone, Serialize)]
+pub struct Stamped<T> {
+    /// Host time, in seconds since the UNIX epoch
+    pub host_ts: Option<f64>,
+    #[serde(flatten)]
+    pub received: Option<ReceiveTime>,
+    #[serde(flatten)]
+    pub message: T,
+}
 
-#[derive(Debug, Clone, Copy)]
+/// Position of a tag with its uncertainty, as published on `points`
+#[derive(Debug, Clone, Serialize)]
 pub struct LocalizedPoint {
-    pub id: usize,
+    pub tag_addr: u16,
     pub point: [f64; 3],
+    pub covariance: [[f64; 3]; 3],
+    pub gdop: f64,
+    pub hdop: f64,
+    pub vdop: f64,
+    pub anchors_used: usize,
+    pub rms_residual: f64,
+    /// Ids of the anchors rejected as outliers
+    pub rejected: Vec<usize>,
 }
 
-/// Synchronize the incoming packets according to the sequence number
-///
-/// This function is called when a new packet arrives from a serial port.
-pub fn synchronize(
-    serial_fifos: &mut Vec<VecDeque<proto::RangeReport>>,
-) -> Option<Vec<proto::RangeReport>> {
-    // Check if all the FIFO queues are non-empty
-    for fifo in serial_fifos.iter() {
-        if fifo.is_empty() {
-            return None;
+impl LocalizedPoint {
+    pub fn new(tag_addr: u16, fix: &optimization::Fix) -> Self {
+        LocalizedPoint {
+            tag_addr,
+            point: fix.solution.position.into(),
+            covariance: fix.covariance.into(),
+            gdop: fix.gdop,
+            hdop: fix.hdop,
+            vdop: fix.vdop,
+            anchors_used: fix.anchors_used,
+            rms_residual: fix.rms_residual,
+            rejected: fix.rejected.clone(),
         }
     }
+}
+
+/// A request received on the command endpoint
+#[derive(Debug, Deserialize)]
+pub struct CommandRequest {
+    /// Index of the serial port to send the command to, all ports if omitted
+    pub port: Option<usize>,
+    #[serde(flatten)]
+    pub command: proto::Command,
+}
+
+/// The reply sent back on the command endpoint
+#[derive(Debug, Default, Serialize)]
+pub struct CommandResponse {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub versions: Vec<(usize, proto::VersionReport)>,
+}
 
-    let mut txts_count = HashMap::<u64, usize>::new();
-    for fifo in serial_fifos.iter() {
-        for report in fifo.iter() {
-            let count = txts_count.entry(report.trigger_txts).or_insert(0);
-            *count += 1;
+impl CommandResponse {
+    fn error(message: impl Into<String>) -> Self {
+        CommandResponse {
+            ok: false,
+            error: Some(message.into()),
+            ..Default::default()
         }
     }
+}
 
-    // If any of the TXTS is present in all the FIFO queues, then we have a match
-    // We drop all the previous packets and return the matched packets
-    let txts_match = txts_count
-        .iter()
-        .find(|(_, &count)| count == serial_fifos.len());
+/// Send a command to the firmware and wait for its answer if there is one
+///
+/// Without a port, the command is sent to all the connected ports.
+async fn execute_command(
+    request: CommandRequest,
+    writers: &mut [Option<SerialWriter>],
+    versions: &broadcast::Sender<(usize, proto::VersionReport)>,
+) -> CommandResponse {
+    let ports: Vec<usize> = match request.port {
+        Some(port) if port >= writers.len() => {
+            return CommandResponse::error(format!("no such port: {}", port))
+        }
+        Some(port) if writers[port].is_none() => {
+            return CommandResponse::error(format!("port {} is disconnected", port))
+        }
+        Some(port) => vec![port],
+        None => (0..writers.len())
+            .filter(|&port| writers[port].is_some())
+            .collect(),
+    };
+
+    // Subscribe before sending so that we cannot miss a fast answer
+    let mut version_rx = versions.subscribe();
+
+    for &port in ports.iter() {
+        let Some(writer) = writers[port].as_mut() else {
+            continue;
+        };
+        if let Err(e) = writer.send(request.command).await {
+            error!("Error writing to serial port {}: {:?}", port, e);
+
+            // The port is reconnected by its reader, with a new writer
+            writers[port] = None;
+            return CommandResponse::error(format!("error writing to port {}: {}", port, e));
+        }
+    }
 
-    if txts_match.is_none() {
-        return None;
+    let mut response = CommandResponse {
+        ok: true,
+        ..Default::default()
+    };
+
+    if request.command == proto::Command::GetVersion {
+        let deadline = tokio::time::Instant::now() + VERSION_TIMEOUT;
+        while response.versions.len() < ports.len() {
+            match tokio::time::timeout_at(deadline, version_rx.recv()).await {
+                Ok(Ok((port, version))) if ports.contains(&port) => {
+                    response.versions.push((port, version));
+                }
+                Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => {}
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => {
+                    response.ok = false;
+                    response.error = Some("timeout waiting for the firmware version".into());
+                    break;
+                }
+            }
+        }
     }
 
-    let txts_match = txts_match.unwrap().0;
+    response
+}
+
+/// Serve the downlink commands received on the ZMQ REQ/REP endpoint
+///
+/// The writers of the serial ports are received on `connections` as the ports
+/// are (re)connected.
+pub async fn serve_commands(
+    mut receiver: tmq::request_reply::RequestReceiver,
+    ports: usize,
+    mut connections: mpsc::UnboundedReceiver<(usize, SerialWriter)>,
+    versions: broadcast::Sender<(usize, proto::VersionReport)>,
+) {
+    let mut writers: Vec<Option<SerialWriter>> = (0..ports).map(|_| None).collect();
 
-    // drop all the previous packets until the TXTS match
-    for fifo in serial_fifos.iter_mut() {
-        while let Some(front) = fifo.front() {
-            if front.trigger_txts == *txts_match {
-                break;
+    loop {
+        let (mut message, sender) = match receiver.recv().await {
+            Ok(request) => request,
+            Err(e) => {
+                error!("Error receiving command: {:?}", e);
+                return;
             }
+        };
 
-            fifo.pop_front();
-        }
-    }
+        let response = match message.pop_front() {
+            Some(frame) => match serde_json::from_slice::<CommandRequest>(&frame[..]) {
+                Ok(request) => {
+                    info!("Command request: {:?}", request);
 
-    // Check if all the FIFO queues are non-empty
-    for fifo in serial_fifos.iter() {
-        if fifo.is_empty() {
-            return None;
-        }
-    }
+                    // Take the writers of the ports connected since the last command
+                    while let Ok((port, writer)) = connections.try_recv() {
+                        writers[port] = Some(writer);
+                    }
 
-    // Now all the FIFO queues have the same TXTS at the front
-    // We can return the packets
-    let mut packets = Vec::new();
-    for fifo in serial_fifos.iter_mut() {
-        packets.push(fifo.pop_front().unwrap());
+                    execute_command(request, &mut writers, &versions).await
+                }
+                Err(e) => CommandResponse::error(format!("invalid request: {}", e)),
+            },
+            None => CommandResponse::error("empty request"),
+        };
+
+        let json = serde_json::to_string(&response).unwrap();
+        receiver = match sender.send(vec![json.into_bytes()].into()).await {
+            Ok(receiver) => receiver,
+            Err(e) => {
+                error!("Error sending command reply: {:?}", e);
+                return;
+            }
+        };
     }
+}
+
+/// Range bias calibration in progress
+pub struct CalibrationMode {
+    pub estimator: calibration::BiasEstimator,
+    pub samples: usize,
+    pub output: PathBuf,
+}
 
-    // Print the statistics for FIFO queues
-    let serial_fifos_depths: Vec<usize> = serial_fifos.iter().map(|x| x.len()).collect();
-    trace!("FIFO queue depths: {:?}", serial_fifos_depths);
+/// Gyroscope bias calibration in progress
+pub struct GyroCalibrationMode {
+    pub estimator: imu::GyroBiasEstimator,
+    pub samples: usize,
+    pub output: PathBuf,
+}
 
-    Some(packets)
+/// Settings of the synchronization and localization pipeline
+pub struct PipelineSettings {
+    pub site: configuration::SiteConfiguration,
+    pub calibration: Option<CalibrationMode>,
+    pub gyro_calibration: Option<GyroCalibrationMode>,
+    pub solver: optimization::SolverOptions,
+    pub tracker: tracking::TrackerOptions,
+    pub fusion: fusion::FusionOptions,
+    pub imu_timing: diagnostics::ImuTimingOptions,
+    pub synchronizer: synchronizer::SynchronizerOptions,
+    pub reconnect: serial_source::ReconnectOptions,
+    pub max_frame_length: usize,
+    pub stats_interval: Duration,
 }
 
 /// Synchronize the incoming packets according to the sequence number
 /// and publish the synchronized packets to the ZMQ publisher
 pub async fn sync_and_publish(
     mut publisher: tmq::publish::Publish,
-    serial_ports: Vec<SerialStream>,
+    serial_ports: Vec<serial_source::PortSettings>,
+    connections: mpsc::UnboundedSender<(usize, SerialWriter)>,
+    versions: broadcast::Sender<(usize, proto::VersionReport)>,
+    settings: PipelineSettings,
 ) {
-    // Create FIFO queue for all the serial ports
-    let mut serial_fifos: Vec<VecDeque<proto::RangeReport>> = Vec::new();
+    let PipelineSettings {
+        mut site,
+        mut calibration,
+        mut gyro_calibration,
+        solver,
+        tracker,
+        fusion,
+        imu_timing,
+        synchronizer: sync_options,
+        reconnect,
+        max_frame_length,
+        stats_interval,
+    } = settings;
+
+    // Group the range reports of all the serial ports
+    let mut synchronizer = synchronizer::Synchronizer::new(serial_ports.len(), sync_options);
+    let mut synchronized_ranges = VecDeque::<synchronizer::SynchronizedRanges>::new();
+
+    // Clock of the device on each serial port
+    let mut clocks = clock::ClockSync::new(serial_ports.len(), clock::ClockOptions::default());
+
+    // Port of the IMU of each tag, whose clock the fusion runs on, the
+    // configured roles until the tag reports
+    let mut imu_ports: HashMap<u16, usize> = serial_ports
+        .iter()
+        .enumerate()
+        .filter_map(|(id, port)| match site.port_role(&port.port) {
+            Some(configuration::PortRole::Tag(tag)) => Some((tag, id)),
+            _ => None,
+        })
+        .collect();
+
     let mut readers = Vec::new();
+    let mut decoder_stats = Vec::new();
     for serial_port in serial_ports {
-        serial_fifos.push(VecDeque::<proto::RangeReport>::new());
-        readers.push(MagicLocStreamDecoder.framed(serial_port).boxed());
+        // The statistics of a port are kept across its reconnections
+        let stats = stream_decoder::DecoderStatsHandle::default();
+        decoder_stats.push(stats.clone());
+
+        let make_decoder = move || {
+            TimestampedDecoder::new(
+                MagicLocPacketDecoder::default()
+                    .with_max_frame_length(max_frame_length)
+                    .with_stats_handle(stats.clone()),
+            )
+        };
+        readers.push(serial_source::supervised(serial_port, reconnect, make_decoder).boxed());
     }
 
     // Listen to all the serial ports
@@ -112,112 +307,392 @@ pub async fn sync_and_publish(
         packet_futures.push(join(ready(id), reader.into_future()));
     }
 
-    let mut last_imu_ts = Option::<u64>::None;
+    // Timing of the IMU samples of each tag
+    let mut imu_timing = diagnostics::ImuTimingMonitor::new(imu_timing);
 
-    loop {
-        // Wait for the next packet to arrive (from any serial port)
-        let (id, (packet, reader)) = packet_futures.next().await.unwrap();
+    // Last accepted position of each tag, to warm start the solver
+    let mut last_positions = HashMap::<u16, Vector3<f64>>::new();
 
-        // Decode the packet
-        let result = packet.unwrap();
-        if result.is_err() {
-            panic!("Error reading from serial port: {:?}", result);
-        }
+    let mut tracker = tracking::Tracker::new(tracker);
+    let mut inertial_fusion = fusion::Fusion::new(fusion);
 
-        let packet = result.unwrap();
+    let mut stats_timer = tokio::time::interval(stats_interval);
+    let mut sync_timer = tokio::time::interval(sync_options.window);
 
-        // print the packet
-        trace!("Packet from {}: {:?}", id, packet);
+    loop {
+        // Process the synchronized range reports
+        while let Some(mut synchronized) = synchronized_ranges.pop_front() {
+            // print the synchronized packets
+            info!("Synchronized packets: {:?}", synchronized);
+            if !synchronized.missing.is_empty() {
+                debug!(
+                    "Ports {:?} missed the trigger {}",
+                    synchronized.missing, synchronized.trigger_txts
+                );
+            }
 
-        let decoded = rzcobs::decode(&packet[4..]);
+            // Align the device clocks on the trigger
+            let ports = synchronized.ports();
+            let timestamps: Vec<_> = ports
+                .iter()
+                .zip(synchronized.reports.iter())
+                .map(|(&port, packet)| (port, packet.system_ts))
+                .collect();
+            clocks.add_trigger(&timestamps);
+
+            let host_times: Vec<f64> = timestamps
+                .iter()
+                .filter_map(|&(port, system_ts)| clocks.host_time(port, system_ts))
+                .collect();
+            let host_ts = (!host_times.is_empty())
+                .then(|| host_times.iter().sum::<f64>() / host_times.len() as f64);
+
+            if let Some(mode) = calibration.as_mut() {
+                for packet in synchronized.reports.iter() {
+                    mode.estimator.add(&site, packet);
+                }
 
-        if let Ok(decoded) = decoded {
-            // Verify that the packet is indeed a RangeReport
-            if &decoded[0..3] == b"RNG".as_slice() {
-                // Use binrw to decode the packet
-                let decoded =
-                    proto::RangeReport::read(&mut binrw::io::Cursor::new(&decoded[..])).unwrap();
+                let samples = mode.estimator.min_samples();
+                info!("Calibration samples: {}/{}", samples, mode.samples);
+                if samples >= mode.samples {
+                    mode.estimator.apply(&mut site);
+                    info!("Range calibration: {:?}", site.range_calibration);
 
-                // print the decoded packet
-                debug!("Decoded packet from {}: {:?}", id, decoded);
+                    match site.save(&mode.output) {
+                        Ok(()) => info!("Calibration written to {:?}", mode.output),
+                        Err(e) => error!("Error writing {:?}: {}", mode.output, e),
+                    }
+                    return;
+                }
+                continue;
+            }
 
-                // Add the packet to the FIFO queue
-                serial_fifos[id].push_back(decoded);
+            for packet in synchronized.reports.iter_mut() {
+                calibration::calibrate_ranges(&site, packet);
+            }
 
-                // Synchronize the packets
-                while let Some(mut packets) = synchronize(&mut serial_fifos) {
-                    // print the synchronized packets
-                    info!("Synchronized packets: {:?}", packets);
+            debug!("Bias subtracted: {:?}", synchronized.reports);
 
-                    for packet in packets.iter_mut() {
-                        packet.ranges.iter_mut().for_each(|x| *x -= 76.80);
+            // Publish the synchronized packets
+            // The receive time of each report is in the message
+            let json = serde_json::to_string(&Stamped {
+                host_ts,
+                received: None,
+                message: &synchronized,
+            })
+            .unwrap();
+            let result = publisher
+                .send(vec![b"ranges".to_vec(), json.into_bytes()])
+                .await;
+            if result.is_err() {
+                error!("Error publishing to ZMQ: {:?}", result);
+            }
+
+            // Localize
+            let mut locations = Vec::new();
+            let mut tracks = Vec::new();
+            for ((&port, packet), &received) in ports
+                .iter()
+                .zip(synchronized.reports.iter())
+                .zip(synchronized.received.iter())
+            {
+                let host_ts = clocks.host_time(port, packet.system_ts);
+                let distances = packet.ranges;
+                let initial = last_positions.get(&packet.tag_addr).copied();
+                let options = optimization::SolverOptions {
+                    height: site.height_constraint(packet.tag_addr),
+                    ..solver
+                };
+                let fix =
+                    optimization::localize_point(&site.anchors, &distances, initial, &options);
+
+                let fix = match fix {
+                    Some(fix) if fix.solution.converged => Some(fix),
+                    fix => {
+                        // Start over from the linearized solution next time
+                        last_positions.remove(&packet.tag_addr);
+
+                        warn!(
+                            "Localization of tag {:?} failed: {:?}",
+                            packet.tag_addr, fix
+                        );
+                        None
                     }
+                };
 
-                    debug!("Bias subtracted: {:?}", packets);
+                if let Some(fix) = &fix {
+                    debug!("Solution of tag {:?}: {:?}", packet.tag_addr, fix);
+                    last_positions.insert(packet.tag_addr, fix.solution.position);
 
-                    // Publish the synchronized packets
-                    let json = serde_json::to_string(&packets).unwrap();
-                    let result = publisher
-                        .send(vec![b"ranges".to_vec(), json.into_bytes()])
-                        .await;
-                    if result.is_err() {
-                        error!("Error publishing to ZMQ: {:?}", result);
+                    if !fix.rejected.is_empty() {
+                        warn!(
+                            "Anchors {:?} rejected for tag {:?}",
+                            fix.rejected, packet.tag_addr
+                        );
                     }
 
-                    // Localize
-                    let mut locations = Vec::new();
-                    for packet in packets.iter_mut() {
-                        let distances = packet.ranges;
-                        let point = optimization::localize_point(&distances);
+                    let location = LocalizedPoint::new(packet.tag_addr, fix);
+
+                    // info
+                    info!(
+                        "Location of tag {:?}: {:?} (GDOP {:.2}, RMS {:.3} m)",
+                        packet.tag_addr, location.point, location.gdop, location.rms_residual
+                    );
+                    locations.push(Stamped {
+                        host_ts,
+                        received: Some(received),
+                        message: location,
+                    });
+                }
 
-                        // Convert to [f64; 3]
-                        let point = point.unwrap_or_else(|| Vector3::new(0.0, 0.0, 0.0));
-                        let point = [point[0] as f64, point[1] as f64, point[2] as f64];
-                        locations.push((packet.tag_addr, point));
+                // Track on the ranges, the system timestamp is in microseconds
+                let time = packet.system_ts as f64 * 1e-6;
+
+                // The fusion runs on the clock of the IMU, which may be on another device
+                let imu_time = imu_ports
+                    .get(&packet.tag_addr)
+                    .and_then(|&imu_port| clocks.convert(port, imu_port, packet.system_ts))
+                    .unwrap_or(time);
+                let rejected = inertial_fusion.update_ranges(
+                    packet.tag_addr,
+                    imu_time,
+                    &site.anchors,
+                    &distances,
+                    fix.as_ref(),
+                );
+                if !rejected.is_empty() {
+                    debug!(
+                        "Ranges of anchors {:?} gated out by the IMU fusion of tag {:?}",
+                        rejected, packet.tag_addr
+                    );
+                }
 
-                        // info
-                        info!("Location of tag {:?}: {:?}", packet.tag_addr, point);
+                let track = tracker.update(
+                    packet.tag_addr,
+                    time,
+                    &site.anchors,
+                    &distances,
+                    options.height,
+                    fix.as_ref(),
+                );
+                if let Some(track) = track {
+                    if !track.ranges_rejected.is_empty() {
+                        debug!(
+                            "Ranges of anchors {:?} gated out for tag {:?}",
+                            track.ranges_rejected, packet.tag_addr
+                        );
                     }
+                    tracks.push(Stamped {
+                        host_ts,
+                        received: Some(received),
+                        message: track,
+                    });
+                }
+            }
 
-                    // send the locations to the publisher as JSON
-                    let json = serde_json::to_string(&locations).unwrap();
-                    let _ = publisher
-                        .send(vec![b"points".to_vec(), json.into_bytes()])
-                        .await;
+            // send the locations to the publisher as JSON
+            let json = serde_json::to_string(&locations).unwrap();
+            let _ = publisher
+                .send(vec![b"points".to_vec(), json.into_bytes()])
+                .await;
 
-                    debug!("Locations: {:0.2?}", locations);
-                }
+            let json = serde_json::to_string(&tracks).unwrap();
+            let _ = publisher
+                .send(vec![b"tracks".to_vec(), json.into_bytes()])
+                .await;
+
+            debug!("Locations: {:0.2?}", locations);
+        }
+
+        // Wait for the next packet to arrive (from any serial port)
+        let (id, (packet, reader)) = tokio::select! {
+            next = packet_futures.next() => next.unwrap(),
+            _ = stats_timer.tick() => {
+                // Publish the link statistics of every serial port
+                let stats: Vec<_> = decoder_stats
+                    .iter()
+                    .map(|handle| handle.snapshot())
+                    .enumerate()
+                    .collect();
+                debug!("Decoder statistics: {:?}", stats);
+
+                let json = serde_json::to_string(&stats).unwrap();
+                let _ = publisher
+                    .send(vec![b"stats".to_vec(), json.into_bytes()])
+                    .await;
+
+                // Publish the IMU timing of every tag
+                let reports = imu_timing.take_reports();
+                debug!("IMU timing: {:?}", reports);
+
+                let json = serde_json::to_string(&reports).unwrap();
+                let _ = publisher
+                    .send(vec![b"imu_timing".to_vec(), json.into_bytes()])
+                    .await;
+
+                // Publish the clock estimate of every serial port
+                let states = clocks.states();
+                debug!("Clocks: {:?}", states);
+
+                let json = serde_json::to_string(&states).unwrap();
+                let _ = publisher
+                    .send(vec![b"clocks".to_vec(), json.into_bytes()])
+                    .await;
+                continue;
+            }
+            _ = sync_timer.tick() => {
+                // Emit the groups still missing reports after the window
+                synchronized_ranges.extend(synchronizer.expire(ReceiveTime::now()));
+                continue;
+            }
+        };
+
+        // Decode the packet
+        let Received {
+            time: received,
+            item: packet,
+        } = match packet {
+            Some(serial_source::PortEvent::Item(item)) => item,
+            Some(serial_source::PortEvent::Connected(writer)) => {
+                info!("Serial port {} connected", id);
+
+                // Hand the new writer over to the downlink
+                let writer = FramedWrite::new(writer, MagicLocStreamEncoder::default());
+                let _ = connections.send((id, writer));
+
+                packet_futures.push(join(ready(id), reader.into_future()));
+                continue;
             }
+            Some(serial_source::PortEvent::Disconnected(e)) => {
+                // The other ports keep running, and this one keeps its index
+                warn!("Serial port {} disconnected: {}, reconnecting", id, e);
 
-            if &decoded[0..3] == b"IMU".as_slice() {
-                // Use binrw to decode the packet
-                let decoded =
-                    proto::ImuReport::read(&mut binrw::io::Cursor::new(&decoded[..])).unwrap();
+                packet_futures.push(join(ready(id), reader.into_future()));
+                continue;
+            }
+            None => {
+                error!("Serial port {} stream ended", id);
+                continue;
+            }
+        };
 
+        // print the packet
+        trace!("Packet from {}: {:?}", id, packet);
+
+        match packet {
+            proto::Packet::Range(decoded) => {
                 // print the decoded packet
                 debug!("Decoded packet from {}: {:?}", id, decoded);
+                clocks.add(id, decoded.system_ts, received.rx_wall);
 
-                // Check the interval between the IMU packets
-                if let Some(last_imu_ts) = last_imu_ts {
-                    let interval = decoded.system_ts - last_imu_ts;
-                    tracing::debug!("IMU interval: {} us", interval);
-
-                    if interval > 1500 {
-                        tracing::error!("IMU interval too large: {} us", interval);
+                // Add the packet to the synchronizer
+                synchronized_ranges.extend(synchronizer.push(id, decoded, received));
+                trace!("Pending synchronizations: {}", synchronizer.pending());
+            }
+            proto::Packet::Imu(decoded) => {
+                // print the decoded packet
+                debug!("Decoded packet from {}: {:?}", id, decoded);
+                clocks.add(id, decoded.system_ts, received.rx_wall);
+                imu_ports.insert(decoded.tag_addr, id);
+
+                // Check the interval between the IMU packets of the tag
+                match imu_timing.add(decoded.tag_addr, decoded.system_ts) {
+                    diagnostics::ImuTimingEvent::Interval(interval) => {
+                        trace!(
+                            "IMU interval of tag {:?}: {} us",
+                            decoded.tag_addr,
+                            interval
+                        )
+                    }
+                    diagnostics::ImuTimingEvent::Gap(interval) => {
+                        warn!("IMU gap of tag {:?}: {} us", decoded.tag_addr, interval)
                     }
+                    diagnostics::ImuTimingEvent::Duplicate => {
+                        warn!("Duplicate IMU timestamp of tag {:?}", decoded.tag_addr)
+                    }
+                    diagnostics::ImuTimingEvent::OutOfOrder(back) => {
+                        warn!(
+                            "IMU sample of tag {:?} out of order by {} us",
+                            decoded.tag_addr, back
+                        )
+                    }
+                    diagnostics::ImuTimingEvent::Reset => {
+                        info!("IMU timestamp reset of tag {:?}", decoded.tag_addr)
+                    }
+                    diagnostics::ImuTimingEvent::First => {}
                 }
 
-                last_imu_ts = Some(decoded.system_ts);
+                let imu_configuration = site.imu_configuration(decoded.tag_addr);
+
+                if let Some(mode) = gyro_calibration.as_mut() {
+                    mode.estimator.add(&imu_configuration.to_physical(&decoded));
+
+                    let samples = mode.estimator.min_samples();
+                    trace!(
+                        "Gyroscope calibration samples: {}/{}",
+                        samples,
+                        mode.samples
+                    );
+                    if samples >= mode.samples {
+                        match mode.estimator.estimate() {
+                            Ok(biases) => {
+                                for (tag, bias) in biases {
+                                    info!("Gyroscope bias of tag {:?}: {:?} rad/s", tag, bias);
+                                    let mut imu = site.imu_configuration(tag);
+                                    imu.gyro.bias = bias;
+                                    site.tag_mut(tag).imu = Some(imu);
+                                }
+
+                                match site.save(&mode.output) {
+                                    Ok(()) => info!("Calibration written to {:?}", mode.output),
+                                    Err(e) => error!("Error writing {:?}: {}", mode.output, e),
+                                }
+                            }
+                            Err(e) => error!("Gyroscope calibration failed: {}", e),
+                        }
+                        return;
+                    }
+                } else {
+                    // Publish the IMU sample in physical units, no synchronization needed
+                    let sample = imu_configuration.convert(&decoded);
+                    let host_ts = clocks.host_time(id, decoded.system_ts);
+                    let json = serde_json::to_string(&Stamped {
+                        host_ts,
+                        received: Some(received),
+                        message: &sample,
+                    })
+                    .unwrap();
 
-                // Publish the IMU packet, no synchronization needed
-                let json = serde_json::to_string(&decoded).unwrap();
+                    let _ = publisher
+                        .send(vec![b"imu".to_vec(), json.into_bytes()])
+                        .await;
 
-                let _ = publisher
-                    .send(vec![b"imu".to_vec(), json.into_bytes()])
-                    .await;
+                    // Fused pose at the IMU rate
+                    if let Some(pose) = inertial_fusion.propagate(&sample) {
+                        trace!("Pose of tag {:?}: {:?}", decoded.tag_addr, pose);
+
+                        let json = serde_json::to_string(&Stamped {
+                            host_ts,
+                            received: Some(received),
+                            message: &pose,
+                        })
+                        .unwrap();
+                        let _ = publisher
+                            .send(vec![b"poses".to_vec(), json.into_bytes()])
+                            .await;
+                    }
+                }
+            }
+            proto::Packet::Version(version) => {
+                info!("Firmware version on port {}: {:?}", id, version);
+
+                // Nobody waiting for the version is not an error
+                let _ = versions.send((id, version));
             }
-        } else {
-            debug!("Decoding error: {:?}", decoded);
+            proto::Packet::Unknown { magic, .. } => {
+                warn!("Unknown packet from {}: {:?}", id, magic);
+            }
+            _ => {}
         }
 
         // add a new future waiting for the next packet
@@ -232,37 +707,129 @@ pub async fn main() {
     // Parse command line
     let opts = command_line::parse();
 
+    if opts.command == Some(command_line::Command::ListPorts) {
+        command_line::list_ports();
+        return;
+    }
+
     info!("Starting with options: {:?}", opts);
 
+    // Load the anchor coordinates
+    let site = match &opts.config {
+        Some(path) => configuration::SiteConfiguration::load(path).unwrap_or_else(|e| {
+            panic!("Error loading site configuration {:?}: {}", path, e);
+        }),
+        None => {
+            warn!("No site configuration given, using the built-in anchor coordinates");
+            configuration::SiteConfiguration::from_coordinates(&configuration::COORDINATES)
+        }
+    };
+    info!("Anchors: {:?}", site.anchors);
+
+    let calibration = opts.calibrate.as_ref().map(|position| {
+        info!("Calibration mode, tag at {:?}", position);
+        CalibrationMode {
+            estimator: calibration::BiasEstimator::new(Vector3::from_column_slice(position)),
+            samples: opts.calibration_samples,
+            output: opts.calibration_output.clone(),
+        }
+    });
+
+    let gyro_calibration = opts.calibrate_gyro.then(|| {
+        info!("Gyroscope calibration mode, keep the tags still");
+        GyroCalibrationMode {
+            estimator: imu::GyroBiasEstimator::default(),
+            samples: opts.gyro_calibration_samples,
+            output: opts.calibration_output.clone(),
+        }
+    });
+
     // Open zmq publisher
-    let publisher = tmq::publish(&Context::new())
+    let context = Context::new();
+    let publisher = tmq::publish(&context)
         .set_sndhwm(4)
         .bind(&opts.zmq_addr)
         .unwrap();
 
-    // Open the supplied serial ports
-    let mut serial_ports = Vec::new();
-    for port in opts.serial_ports {
-        let serial_port = tokio_serial::new(port.to_owned(), 921600).open_native();
-        let mut serial_port = serial_port.unwrap();
-
-        // Set the serial port to low latency mode
-        serialport_low_latency::enable_low_latency(&mut serial_port).unwrap();
-
-        drop(serial_port);
-
-        let serial_port = tokio_serial::new(port, 921600)
-            .timeout(Duration::from_millis(10))
-            .open_native_async()
-            .unwrap();
-
-        serial_port.clear(tokio_serial::ClearBuffer::Input).unwrap();
-
-        serial_ports.push(serial_port);
+    // Open the zmq command endpoint
+    let command_receiver = tmq::reply(&context).bind(&opts.zmq_cmd_addr).unwrap();
+
+    let solver = optimization::SolverOptions {
+        max_iterations: opts.max_iterations,
+        step_tolerance: opts.step_tolerance,
+        range_sigma: opts.range_sigma,
+        loss: opts.loss(),
+        outlier_threshold: opts.outlier_threshold,
+        ..Default::default()
+    };
+
+    let tracker = tracking::TrackerOptions {
+        model: opts.motion_model(),
+        process_noise: opts.process_noise,
+        range_sigma: opts.range_sigma,
+        gate: opts.gate,
+        ..Default::default()
+    };
+
+    // The serial ports are opened, and reopened on failure, by their readers
+    let serial_ports = match opts.serial.port_settings(&site) {
+        Ok(serial_ports) => serial_ports,
+        Err(e) => {
+            error!("Invalid serial ports: {}", e);
+            return;
+        }
+    };
+    if serial_ports.is_empty() {
+        error!("No serial ports, give them with --serial-ports or in the site configuration");
+        return;
     }
+    for (id, settings) in serial_ports.iter().enumerate() {
+        match site.port_role(&settings.port) {
+            Some(role) => info!("Serial port {} ({}): {:?}", id, role, settings),
+            None => info!("Serial port {}: {:?}", id, settings),
+        }
+    }
+
+    // serve the downlink commands
+    let (connections, connection_receiver) = mpsc::unbounded_channel();
+    let (versions, _) = broadcast::channel(16);
+    tokio::spawn(serve_commands(
+        command_receiver,
+        serial_ports.len(),
+        connection_receiver,
+        versions.clone(),
+    ));
 
     // synchronize and publish the packets
-    tokio::spawn(sync_and_publish(publisher, serial_ports))
-        .await
-        .unwrap();
+    tokio::spawn(sync_and_publish(
+        publisher,
+        serial_ports,
+        connections,
+        versions,
+        PipelineSettings {
+            site,
+            calibration,
+            gyro_calibration,
+            solver,
+            tracker,
+            fusion: fusion::FusionOptions {
+                range_sigma: opts.range_sigma,
+                gate: opts.gate,
+                ..Default::default()
+            },
+            imu_timing: diagnostics::ImuTimingOptions {
+                gap_threshold_us: opts.imu_gap_threshold,
+                ..Default::default()
+            },
+            synchronizer: synchronizer::SynchronizerOptions {
+                window: Duration::from_secs_f64(opts.sync_window),
+                max_pending: opts.sync_max_pending,
+            },
+            reconnect: serial_source::ReconnectOptions::default(),
+            max_frame_length: opts.max_frame_length,
+            stats_interval: Duration::from_secs_f64(opts.stats_interval),
+        },
+    ))
+    .await
+    .unwrap();
 }
@@ -1,36 +1,46 @@
-use binrw::BinRead;
 use futures::{
     future::{join, ready},
     stream::FuturesUnordered,
     StreamExt,
 };
-use magic_loc_central::{proto, stream_decoder::MagicLocStreamDecoder};
+use magic_loc_central::{
+    command_line,
+    configuration::SiteConfiguration,
+    proto,
+    serial_source::{self, PortEvent, ReconnectOptions},
+    stream_decoder::{self, MagicLocPacketDecoder, Received, TimestampedDecoder},
+};
 use tokio;
-use tokio_util::codec::Decoder;
 
-use std::{collections::VecDeque, time::Duration};
+use std::path::PathBuf;
 
 use clap::Parser;
 
 use serde_json;
 
 // tracing
-use tracing::{debug, error, info, trace};
-
-// serialport
-use serialport_low_latency;
-use tokio_serial::{self, SerialPort, SerialPortBuilderExt, SerialStream};
+use tracing::{debug, error, info, trace, warn};
 
 #[derive(Parser, Debug)]
 #[clap(version, about, long_about = None)]
 pub struct Options {
+    #[command(subcommand)]
+    pub command: Option<command_line::Command>,
+
     /// Increase verbosity, and can be used multiple times
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
 
-    /// Serial port devices
-    #[arg(short, long, required = true, num_args = 1..)]
-    pub serial_ports: Vec<String>,
+    #[command(flatten)]
+    pub serial: command_line::SerialOptions,
+
+    /// Site configuration file (TOML or JSON) with the serial ports
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Maximum frame length in bytes, longer frames are dropped
+    #[arg(long, default_value_t = stream_decoder::DEFAULT_MAX_FRAME_LENGTH)]
+    pub max_frame_length: usize,
 }
 
 #[tokio::main]
@@ -38,6 +48,11 @@ pub async fn main() {
     // Parse command line
     let opts = Options::parse();
 
+    if opts.command == Some(command_line::Command::ListPorts) {
+        command_line::list_ports();
+        return;
+    }
+
     let debug_level = match opts.verbose {
         0 => tracing::Level::INFO,
         1 => tracing::Level::DEBUG,
@@ -47,28 +62,38 @@ pub async fn main() {
 
     info!("Starting with options: {:?}", opts);
 
-    // Open the supplied serial ports
-    let mut serial_ports = Vec::new();
-    for port in opts.serial_ports {
-        let serial_port = tokio_serial::new(port.to_owned(), 921600).open_native();
-        let mut serial_port = serial_port.unwrap();
-
-        // Set the serial port to low latency mode
-        serialport_low_latency::enable_low_latency(&mut serial_port).unwrap();
-
-        drop(serial_port);
-
-        let serial_port = tokio_serial::new(port, 2000000)
-            .timeout(Duration::from_millis(10))
-            .open_native_async()
-            .unwrap();
+    let site = match &opts.config {
+        Some(path) => SiteConfiguration::load(path).unwrap_or_else(|e| {
+            panic!("Error loading site configuration {:?}: {}", path, e);
+        }),
+        None => SiteConfiguration::default(),
+    };
 
-        serial_ports.push(serial_port);
+    let serial_ports = match opts.serial.port_settings(&site) {
+        Ok(serial_ports) => serial_ports,
+        Err(e) => {
+            error!("Invalid serial ports: {}", e);
+            return;
+        }
+    };
+    if serial_ports.is_empty() {
+        error!("No serial ports, give them with --serial-ports or in the site configuration");
+        return;
     }
 
+    // The serial ports are opened, and reopened on failure, by their readers
     let mut readers = Vec::new();
-    for serial_port in serial_ports {
-        readers.push(MagicLocStreamDecoder.framed(serial_port).boxed());
+    for settings in serial_ports {
+        info!("Serial port {}: {:?}", readers.len(), settings);
+        let max_frame_length = opts.max_frame_length;
+        let make_decoder = move || {
+            TimestampedDecoder::new(
+                MagicLocPacketDecoder::default().with_max_frame_length(max_frame_length),
+            )
+        };
+        readers.push(
+            serial_source::supervised(settings, ReconnectOptions::default(), make_decoder).boxed(),
+        );
     }
 
     // Listen to all the serial ports
@@ -82,35 +107,47 @@ pub async fn main() {
         let (id, (packet, reader)) = packet_futures.next().await.unwrap();
 
         // Decode the packet
-        let result = packet.unwrap();
-        if result.is_err() {
-            panic!("Error reading from serial port: {:?}", result);
-        }
-
-        let packet = result.unwrap();
+        let Received {
+            time: received,
+            item: packet,
+        } = match packet {
+            Some(PortEvent::Item(item)) => item,
+            Some(PortEvent::Connected(_)) => {
+                info!("Serial port {} connected", id);
+                packet_futures.push(join(ready(id), reader.into_future()));
+                continue;
+            }
+            Some(PortEvent::Disconnected(e)) => {
+                warn!("Serial port {} disconnected: {}, reconnecting", id, e);
+                packet_futures.push(join(ready(id), reader.into_future()));
+                continue;
+            }
+            None => {
+                error!("Serial port {} stream ended", id);
+                continue;
+            }
+        };
 
         // print the packet
         trace!("Packet from {}: {:?}", id, packet);
 
-        let decoded = rzcobs::decode(&packet[4..]);
-
-        if let Ok(decoded) = decoded {
-            // CirReport
-            if &decoded[0..3] == b"CIR".as_slice() {
-                // Use binrw to decode the packet
-                let decoded =
-                    proto::CirReport::read(&mut binrw::io::Cursor::new(&decoded[..])).unwrap();
-
+        match packet {
+            proto::Packet::Cir(decoded) => {
                 let cir_report = proto::ConvertedCirReport::from(decoded);
 
                 // print the decoded packet
                 debug!("Decoded packet from {}: {:?}", id, cir_report);
 
-                // Convert to JSON
-                let json = serde_json::to_string(&cir_report).unwrap();
+                // Convert to JSON, with the host receive time
+                let json = serde_json::to_string(&Received {
+                    time: received,
+                    item: cir_report,
+                })
+                .unwrap();
                 println!("{}", json);
-            } else {
-                error!("Unknown packet: {:?}", decoded);
+            }
+            _ => {
+                error!("Unknown packet: {:?}", packet);
             }
         }
 
@@ -0,0 +1,173 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use clap::Parser;
+use futures::{FutureExt, StreamExt};
+use magic_loc_central::{
+    configuration::{Anchor, SiteConfiguration},
+    survey::{self, RangeSurvey},
+    synchronizer,
+};
+use tmq::{self, Context};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::{debug, error, info, warn};
+
+#[derive(Parser, Debug)]
+#[clap(version, about, long_about = None)]
+pub struct Options {
+    /// Increase verbosity, and can be used multiple times
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// ZMQ address of magic-loc-central
+    #[arg(short, long, default_value = "tcp://localhost:5555")]
+    pub zmq_addr: String,
+
+    /// Site configuration to update, all its anchors are surveyed
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Number of anchors, surveyed as ids 0..N when no site configuration is given
+    #[arg(short, long, default_value_t = 8)]
+    pub anchors: usize,
+
+    /// Address of the tag used for the survey, the first tag seen if omitted
+    #[arg(short, long)]
+    pub tag: Option<u16>,
+
+    /// Number of range reports collected with the tag at each anchor
+    #[arg(long, default_value_t = 100)]
+    pub samples: usize,
+
+    /// Anchor ids defining the frame: origin, x axis and xy plane
+    #[arg(long, num_args = 3, default_values_t = [0, 1, 2])]
+    pub frame: Vec<usize>,
+
+    /// Site configuration file to write
+    #[arg(short, long, default_value = "site-surveyed.toml")]
+    pub output: PathBuf,
+}
+
+#[tokio::main]
+pub async fn main() {
+    // Parse command line
+    let opts = Options::parse();
+
+    let debug_level = match opts.verbose {
+        0 => tracing::Level::INFO,
+        1 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
+    };
+    tracing_subscriber::fmt().with_max_level(debug_level).init();
+
+    info!("Starting with options: {:?}", opts);
+
+    // Anchors to survey, their index in the survey is their position in the list
+    let mut site = match &opts.config {
+        Some(path) => SiteConfiguration::load(path).unwrap_or_else(|e| {
+            panic!("Error loading site configuration {:?}: {}", path, e);
+        }),
+        None => SiteConfiguration {
+            anchors: (0..opts.anchors)
+                .map(|id| Anchor {
+                    id,
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                    metadata: BTreeMap::new(),
+                })
+                .collect(),
+            ..Default::default()
+        },
+    };
+    let ids: Vec<usize> = site.anchors.iter().map(|anchor| anchor.id).collect();
+
+    let frame: Vec<usize> = opts
+        .frame
+        .iter()
+        .map(|id| {
+            ids.iter()
+                .position(|x| x == id)
+                .unwrap_or_else(|| panic!("Frame anchor {} is not surveyed", id))
+        })
+        .collect();
+
+    // Subscribe to the ranges published by magic-loc-central
+    let mut subscriber = tmq::subscribe(&Context::new())
+        .connect(&opts.zmq_addr)
+        .unwrap()
+        .subscribe(b"ranges")
+        .unwrap();
+
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let mut survey = RangeSurvey::new(ids.len());
+    let mut tag = opts.tag;
+
+    for (index, &id) in ids.iter().enumerate() {
+        println!("Place the tag at anchor {} and press Enter", id);
+        let _ = stdin.next_line().await;
+
+        // Drop the ranges received while the tag was moved
+        while let Some(Some(_)) = subscriber.next().now_or_never() {}
+
+        let mut samples = 0;
+        while samples < opts.samples {
+            let message = match subscriber.next().await {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => {
+                    error!("Error receiving from ZMQ: {:?}", e);
+                    continue;
+                }
+                None => panic!("ZMQ subscription closed"),
+            };
+
+            let Some(json) = message.0.get(1) else {
+                continue;
+            };
+            let synchronized: synchronizer::SynchronizedRanges =
+                match serde_json::from_slice(&json[..]) {
+                    Ok(synchronized) => synchronized,
+                    Err(e) => {
+                        warn!("Invalid ranges message: {}", e);
+                        continue;
+                    }
+                };
+
+            for report in synchronized.reports.iter() {
+                let survey_tag = *tag.get_or_insert(report.tag_addr);
+                if report.tag_addr != survey_tag {
+                    continue;
+                }
+
+                debug!("Ranges at anchor {}: {:?}", id, report.ranges);
+                for (other, &other_id) in ids.iter().enumerate() {
+                    if let Some(&range) = report.ranges.get(other_id) {
+                        survey.add(index, other, range);
+                    }
+                }
+                samples += 1;
+            }
+        }
+        info!("Collected {} range reports at anchor {}", samples, id);
+    }
+
+    let measurements = survey.measurements();
+    for m in measurements.iter() {
+        info!("Range {} <-> {}: {:.3} m", ids[m.from], ids[m.to], m.range);
+    }
+
+    let positions =
+        survey::survey_anchors(ids.len(), &measurements, (frame[0], frame[1], frame[2]))
+            .unwrap_or_else(|e| panic!("Survey failed: {}", e));
+
+    for (anchor, position) in site.anchors.iter_mut().zip(positions.iter()) {
+        anchor.x = position.x;
+        anchor.y = position.y;
+        anchor.z = position.z;
+        info!("Anchor {}: {:+.3?}", anchor.id, position);
+    }
+
+    match site.save(&opts.output) {
+        Ok(()) => info!("Site configuration written to {:?}", opts.output),
+        Err(e) => error!("Error writing {:?}: {}", opts.output, e),
+    }
+}
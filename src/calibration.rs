@@ -0,0 +1,162 @@
+// Range calibration
+//
+// Corrects the raw ranges with the per (tag, anchor) bias and scale of the site
+// configuration, and estimates the biases from a tag placed at a surveyed position.
+
+use std::collections::BTreeMap;
+
+use nalgebra::Vector3;
+
+use crate::{
+    configuration::{RangeCalibration, SiteConfiguration},
+    proto::RangeReport,
+};
+
+/// Apply the range calibration of the site to a report, in place
+pub fn calibrate_ranges(site: &SiteConfiguration, report: &mut RangeReport) {
+    for (anchor, range) in report.ranges.iter_mut().enumerate() {
+        let (bias, scale) = site.range_correction(report.tag_addr, anchor);
+        *range = (*range - bias) * scale;
+    }
+}
+
+/// Estimate the range biases of the tags placed at a known position
+///
+/// The bias of each (tag, anchor) pair is the median of the difference between
+/// the raw range and the true distance, which is robust to occasional NLOS ranges.
+pub struct BiasEstimator {
+    position: Vector3<f64>,
+    /// Raw range minus true distance, per tag and anchor
+    errors: BTreeMap<u16, BTreeMap<usize, Vec<f64>>>,
+}
+
+impl BiasEstimator {
+    pub fn new(position: Vector3<f64>) -> Self {
+        BiasEstimator {
+            position,
+            errors: BTreeMap::new(),
+        }
+    }
+
+    /// Add an uncalibrated range report
+    pub fn add(&mut self, site: &SiteConfiguration, report: &RangeReport) {
+        let tag_errors = self.errors.entry(report.tag_addr).or_default();
+
+        for anchor in site.anchors.iter() {
+            let Some(&range) = report.ranges.get(anchor.id) else {
+                continue;
+            };
+            if !range.is_normal() {
+                continue;
+            }
+
+            let distance = (anchor.position() - self.position).norm();
+            tag_errors
+                .entry(anchor.id)
+                .or_default()
+                .push(range - distance);
+        }
+    }
+
+    /// Smallest number of samples of all the (tag, anchor) pairs seen so far
+    pub fn min_samples(&self) -> usize {
+        self.errors
+            .values()
+            .flat_map(|tag_errors| tag_errors.values())
+            .map(|errors| errors.len())
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Estimate the range calibration of all the (tag, anchor) pairs seen so far
+    pub fn estimate(&self) -> Vec<RangeCalibration> {
+        let mut calibration = Vec::new();
+
+        for (&tag, tag_errors) in self.errors.iter() {
+            for (&anchor, errors) in tag_errors.iter() {
+                let mut errors = errors.clone();
+                errors.sort_by(f64::total_cmp);
+
+                let n = errors.len();
+                let bias = (errors[(n - 1) / 2] + errors[n / 2]) / 2.0;
+
+                calibration.push(RangeCalibration {
+                    tag: Some(tag),
+                    anchor,
+                    bias,
+                    scale: 1.0,
+                });
+            }
+        }
+
+        calibration
+    }
+
+    /// Replace the calibration of the estimated tags in the site configuration
+    pub fn apply(&self, site: &mut SiteConfiguration) {
+        site.range_calibration.retain(|entry| match entry.tag {
+            Some(tag) => !self.errors.contains_key(&tag),
+            None => true,
+        });
+        site.range_calibration.extend(self.estimate());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bias_estimation() {
+        let mut site = SiteConfiguration::from_coordinates(&[
+            (0.0, 0.0, 1.0),
+            (5.0, 0.0, 1.0),
+            (0.0, 5.0, 1.0),
+        ]);
+        let position = Vector3::new(1.0, 2.0, 0.5);
+        let biases = [76.5, 77.0, 76.8];
+
+        let mut estimator = BiasEstimator::new(position);
+        for i in 0..21 {
+            let mut report = RangeReport {
+                tag_addr: 0x0134,
+                ranges: [f64::NAN; 8],
+                ..Default::default()
+            };
+            for (anchor, bias) in site.anchors.iter().zip(biases) {
+                // Symmetric noise, and one large NLOS outlier
+                let noise = if i == 0 {
+                    5.0
+                } else {
+                    0.01 * (i % 5) as f64 - 0.02
+                };
+                report.ranges[anchor.id] = (anchor.position() - position).norm() + bias + noise;
+            }
+            estimator.add(&site, &report);
+        }
+        assert_eq!(estimator.min_samples(), 21);
+
+        estimator.apply(&mut site);
+        assert_eq!(site.range_calibration.len(), 3);
+        for (anchor, bias) in biases.iter().enumerate() {
+            let (estimated, scale) = site.range_correction(0x0134, anchor);
+            assert!((estimated - bias).abs() < 1e-9);
+            assert_eq!(scale, 1.0);
+        }
+
+        // The calibrated ranges match the true distances
+        let mut report = RangeReport {
+            tag_addr: 0x0134,
+            ranges: [f64::NAN; 8],
+            ..Default::default()
+        };
+        for (anchor, bias) in site.anchors.iter().zip(biases) {
+            report.ranges[anchor.id] = (anchor.position() - position).norm() + bias;
+        }
+        calibrate_ranges(&site, &mut report);
+        for anchor in site.anchors.iter() {
+            let distance = (anchor.position() - position).norm();
+            assert!((report.ranges[anchor.id] - distance).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,303 @@
+// Device clock estimation
+//
+// Relates the system timestamps of the devices on the serial ports to the host
+// clock. Each device clock is modeled as an offset and a skew, fitted to the
+// lower envelope of the host receive times (the reports with the least
+// latency), and the devices are aligned with each other on the triggers they
+// report together.
+
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+/// Options of the clock models
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockOptions {
+    /// Duration of the buckets keeping the report of least latency, in seconds
+    pub bucket: f64,
+    /// Number of buckets the offset and skew are fitted on
+    pub buckets: usize,
+    /// Largest skew between a device and the host
+    pub max_skew: f64,
+    /// A device timestamp going back by more than this is a reset, in seconds
+    pub reset_threshold: f64,
+    /// Gain of the alignment of the devices on the shared triggers, in 0..1
+    pub trigger_gain: f64,
+}
+
+impl Default for ClockOptions {
+    fn default() -> Self {
+        ClockOptions {
+            bucket: 1.0,
+            buckets: 60,
+            max_skew: 500e-6,
+            reset_threshold: 1.0,
+            trigger_gain: 0.05,
+        }
+    }
+}
+
+/// Clock estimate of a serial port, as published on `clocks`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ClockState {
+    pub port: usize,
+    /// Host time minus device time at the last report, in seconds
+    pub offset: Option<f64>,
+    /// Skew of the device clock relative to the host, positive when fast, in ppm
+    pub skew_ppm: f64,
+    /// Alignment correction from the shared triggers, in seconds
+    pub correction: f64,
+    /// Number of buckets of the fit
+    pub points: usize,
+    pub resets: u64,
+}
+
+/// Report of least latency of a bucket, relative to the clock origin
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    bucket: i64,
+    /// Device time, in seconds
+    device: f64,
+    /// Host time minus device time, in seconds
+    offset: f64,
+}
+
+/// Offset and skew model of a device clock
+#[derive(Debug, Clone, Default)]
+pub struct ClockModel {
+    /// Device time in microseconds and host time in seconds of the first report
+    origin: Option<(u64, f64)>,
+    last_device_us: u64,
+    points: VecDeque<Point>,
+    /// Offset at the origin and skew, `host - device = intercept + skew * device`
+    intercept: f64,
+    skew: f64,
+    correction: f64,
+    resets: u64,
+}
+
+impl ClockModel {
+    /// Device time since the origin, in seconds
+    fn device_time(&self, device_us: u64, origin_us: u64) -> f64 {
+        (device_us as f64 - origin_us as f64) * 1e-6
+    }
+
+    /// Add a report timestamped by the device, received at `host` time
+    pub fn add(&mut self, device_us: u64, host: f64, options: &ClockOptions) {
+        if let Some((origin_us, _)) = self.origin {
+            let back = self.last_device_us.saturating_sub(device_us) as f64 * 1e-6;
+            if back > options.reset_threshold || device_us < origin_us {
+                // The device restarted, its clock as well
+                *self = ClockModel {
+                    resets: self.resets + 1,
+                    ..Default::default()
+                };
+            }
+        }
+
+        let (origin_us, origin_host) = *self.origin.get_or_insert((device_us, host));
+        self.last_device_us = self.last_device_us.max(device_us);
+
+        let device = self.device_time(device_us, origin_us);
+        let point = Point {
+            bucket: (device / options.bucket).floor() as i64,
+            device,
+            offset: host - origin_host - device,
+        };
+
+        match self.points.back_mut() {
+            Some(last) if last.bucket == point.bucket => {
+                if point.offset < last.offset {
+                    *last = point;
+                }
+            }
+            _ => {
+                self.points.push_back(point);
+                while self.points.len() > options.buckets.max(1) {
+                    self.points.pop_front();
+                }
+            }
+        }
+
+        self.fit(options);
+    }
+
+    /// Fit the offset and skew on the bucket points (least squares)
+    fn fit(&mut self, options: &ClockOptions) {
+        let n = self.points.len() as f64;
+        let mean_device = self.points.iter().map(|p| p.device).sum::<f64>() / n;
+        let mean_offset = self.points.iter().map(|p| p.offset).sum::<f64>() / n;
+
+        let mut covariance = 0.0;
+        let mut variance = 0.0;
+        for point in self.points.iter() {
+            covariance += (point.device - mean_device) * (point.offset - mean_offset);
+            variance += (point.device - mean_device).powi(2);
+        }
+
+        self.skew = if variance > 0.0 {
+            (covariance / variance).clamp(-options.max_skew, options.max_skew)
+        } else {
+            0.0
+        };
+        self.intercept = mean_offset - self.skew * mean_device;
+    }
+
+    /// Host time of a device timestamp, without the trigger alignment
+    fn raw_host_time(&self, device_us: u64) -> Option<f64> {
+        let (origin_us, origin_host) = self.origin?;
+        let device = self.device_time(device_us, origin_us);
+
+        Some(origin_host + device + self.intercept + self.skew * device)
+    }
+
+    /// Host time of a device timestamp, in seconds since the UNIX epoch
+    pub fn host_time(&self, device_us: u64) -> Option<f64> {
+        Some(self.raw_host_time(device_us)? - self.correction)
+    }
+
+    /// Device time of a host time, in seconds
+    pub fn device_time_at(&self, host: f64) -> Option<f64> {
+        let (origin_us, origin_host) = self.origin?;
+        let device = (host + self.correction - origin_host - self.intercept) / (1.0 + self.skew);
+
+        Some(origin_us as f64 * 1e-6 + device)
+    }
+}
+
+/// Clock models of all the serial ports
+pub struct ClockSync {
+    options: ClockOptions,
+    clocks: Vec<ClockModel>,
+}
+
+impl ClockSync {
+    pub fn new(ports: usize, options: ClockOptions) -> Self {
+        ClockSync {
+            options,
+            clocks: vec![ClockModel::default(); ports],
+        }
+    }
+
+    /// Add a report of a port, timestamped by the device and received at `host` time
+    pub fn add(&mut self, port: usize, device_us: u64, host: f64) {
+        self.clocks[port].add(device_us, host, &self.options);
+    }
+
+    /// Align the ports on the timestamps of the same trigger
+    ///
+    /// The reports of a trigger are taken as simultaneous, and the corrections
+    /// average to zero, so that the common time stays on the host clock.
+    pub fn add_trigger(&mut self, reports: &[(usize, u64)]) {
+        let times: Vec<(usize, f64)> = reports
+            .iter()
+            .filter_map(|&(port, device_us)| {
+                Some((port, self.clocks[port].raw_host_time(device_us)?))
+            })
+            .collect();
+        if times.len() < 2 {
+            return;
+        }
+
+        let mean = times.iter().map(|(_, time)| time).sum::<f64>() / times.len() as f64;
+        for (port, time) in times {
+            let clock = &mut self.clocks[port];
+            clock.correction += self.options.trigger_gain * (time - mean - clock.correction);
+        }
+    }
+
+    /// Host time of a device timestamp of a port, in seconds since the UNIX epoch
+    pub fn host_time(&self, port: usize, device_us: u64) -> Option<f64> {
+        self.clocks[port].host_time(device_us)
+    }
+
+    /// Time of the device of port `to` at a timestamp of the device of port `from`, in seconds
+    pub fn convert(&self, from: usize, to: usize, device_us: u64) -> Option<f64> {
+        if from == to {
+            return Some(device_us as f64 * 1e-6);
+        }
+        self.clocks[to].device_time_at(self.host_time(from, device_us)?)
+    }
+
+    pub fn states(&self) -> Vec<ClockState> {
+        self.clocks
+            .iter()
+            .enumerate()
+            .map(|(port, clock)| ClockState {
+                port,
+                offset: clock
+                    .host_time(clock.last_device_us)
+                    .map(|host| host - clock.last_device_us as f64 * 1e-6),
+                skew_ppm: -clock.skew * 1e6,
+                correction: clock.correction,
+                points: clock.points.len(),
+                resets: clock.resets,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_model() {
+        let options = ClockOptions::default();
+        let mut clock = ClockModel::default();
+        assert_eq!(clock.host_time(0), None);
+
+        // Device clock 50 ppm fast, latency between 1 and 5 ms
+        let epoch = 1.7e9;
+        let skew = 50e-6;
+        let device_us = |t: f64| ((5.0 + t * (1.0 + skew)) * 1e6) as u64;
+        for k in 0..60_000 {
+            let t = k as f64 * 1e-3;
+            let latency = 1e-3 + 4e-3 * ((k * 7) % 10) as f64 / 10.0;
+            clock.add(device_us(t), epoch + t + latency, &options);
+        }
+
+        // Within the minimal latency of the host time
+        let host = clock.host_time(device_us(60.0)).unwrap();
+        assert!((host - (epoch + 60.0 + 1e-3)).abs() < 1e-4);
+        assert!((clock.skew + skew).abs() < 2e-6);
+
+        let device = clock.device_time_at(epoch + 30.0 + 1e-3).unwrap();
+        assert!((device - (5.0 + 30.0 * (1.0 + skew))).abs() < 1e-4);
+
+        // A reboot of the device restarts the model
+        clock.add(1000, epoch + 61.0, &options);
+        assert_eq!(clock.resets, 1);
+        assert_eq!(clock.host_time(1000), Some(epoch + 61.0));
+    }
+
+    #[test]
+    fn test_clock_sync() {
+        let mut sync = ClockSync::new(2, ClockOptions::default());
+        let epoch = 1.7e9;
+
+        // Port 1 always has 2 ms more latency, and a different clock origin
+        for k in 0..1000 {
+            let t = k as f64 * 0.01;
+            let device_0 = (t * 1e6) as u64;
+            let device_1 = ((t + 100.0) * 1e6) as u64;
+            sync.add(0, device_0, epoch + t + 1e-3);
+            sync.add(1, device_1, epoch + t + 3e-3);
+            sync.add_trigger(&[(0, device_0), (1, device_1)]);
+        }
+
+        // Aligned on the triggers, between the two latencies
+        let time_0 = sync.host_time(0, 5_000_000).unwrap();
+        let time_1 = sync.host_time(1, 105_000_000).unwrap();
+        assert!((time_0 - time_1).abs() < 1e-5);
+        assert!((time_0 - (epoch + 5.0 + 2e-3)).abs() < 1e-4);
+
+        let device = sync.convert(0, 1, 5_000_000).unwrap();
+        assert!((device - 105.0).abs() < 1e-5);
+        assert_eq!(sync.convert(1, 1, 5_000_000), Some(5.0));
+
+        let states = sync.states();
+        assert_eq!(states.len(), 2);
+        assert!((states[1].correction - 1e-3).abs() < 1e-4);
+    }
+}
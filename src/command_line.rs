@@ -1,8 +1,120 @@
-use clap::Parser;
+use std::{path::PathBuf, time::Duration};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::{
+    configuration::{ConfigurationError, SiteConfiguration},
+    optimization, port_selector,
+    port_selector::PortSelector,
+    serial_source::{self, FlowControl, PortSettings},
+    stream_decoder, tracking,
+};
+
+/// Loss applied to the range residuals by the localization solver
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LossFunction {
+    Squared,
+    Huber,
+    Cauchy,
+}
+
+/// Motion model of the tag tracker
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum TrackingModel {
+    /// Constant velocity
+    Cv,
+    /// Constant acceleration
+    Ca,
+}
+
+// Commands run instead of the localization (the types flattened into the
+// options have no doc comments, which clap would take as the about)
+#[derive(Subcommand, Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// List the serial ports attached, with their USB identity
+    ListPorts,
+}
+
+// Serial port options, shared by the binaries
+#[derive(Args, Debug, Clone)]
+pub struct SerialOptions {
+    /// Serial ports: device path, serial=<USB serial number>, <vid>:<pid> or
+    /// pattern (e.g. "/dev/serial/by-id/usb-SEGGER*"), the ports of the site
+    /// configuration if omitted
+    #[arg(short, long, num_args = 1..)]
+    pub serial_ports: Vec<PortSelector>,
+
+    /// Baud rate of all the serial ports [default: 921600, or the site configuration]
+    #[arg(long)]
+    pub baud_rate: Option<u32>,
+
+    /// Flow control of all the serial ports [default: none, or the site configuration]
+    #[arg(long, value_enum)]
+    pub flow_control: Option<FlowControl>,
+
+    /// Read and write timeout of all the serial ports, in milliseconds [default: 10, or the site configuration]
+    #[arg(long)]
+    pub serial_timeout: Option<u64>,
+
+    /// Do not enable the low latency mode of the USB serial adapters
+    #[arg(long)]
+    pub no_low_latency: bool,
+}
+
+impl SerialOptions {
+    /// Settings of the serial ports, the ports of the site if none are given
+    ///
+    /// The ports are ordered by their role, see `SiteConfiguration::order_ports`.
+    /// The command line takes precedence over the settings of the port in the
+    /// site configuration, which take precedence over the defaults.
+    pub fn port_settings(
+        &self,
+        site: &SiteConfiguration,
+    ) -> Result<Vec<PortSettings>, ConfigurationError> {
+        let ports = if self.serial_ports.is_empty() {
+            site.ports.iter().map(|port| port.device.clone()).collect()
+        } else {
+            self.serial_ports.clone()
+        };
+
+        let settings = site
+            .order_ports(ports)?
+            .into_iter()
+            .map(|port| {
+                let configured = site.port(&port);
+                let baud_rate = self
+                    .baud_rate
+                    .or(configured.and_then(|entry| entry.baud_rate))
+                    .unwrap_or(serial_source::DEFAULT_BAUD_RATE);
+                let flow_control = self
+                    .flow_control
+                    .or(configured.and_then(|entry| entry.flow_control));
+                let timeout = self
+                    .serial_timeout
+                    .or(configured.and_then(|entry| entry.timeout_ms));
+                let low_latency = configured.and_then(|entry| entry.low_latency);
+
+                let mut settings = PortSettings::new(port, baud_rate);
+                if let Some(flow_control) = flow_control {
+                    settings.flow_control = flow_control;
+                }
+                if let Some(timeout) = timeout {
+                    settings.timeout = Duration::from_millis(timeout);
+                }
+                settings.low_latency = !self.no_low_latency && low_latency != Some(false);
+                settings
+            })
+            .collect();
+        Ok(settings)
+    }
+}
 
 #[derive(Parser, Debug)]
 #[clap(version, about, long_about = None)]
 pub struct Options {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Increase verbosity, and can be used multiple times
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
@@ -11,9 +123,127 @@ pub struct Options {
     #[arg(short, long, default_value = "tcp://*:5555")]
     pub zmq_addr: String,
 
-    /// Serial port devices
-    #[arg(short, long, required = true, num_args = 1..)]
-    pub serial_ports: Vec<String>,
+    /// ZMQ command (REQ/REP) listen address
+    #[arg(long, default_value = "tcp://*:5556")]
+    pub zmq_cmd_addr: String,
+
+    #[command(flatten)]
+    pub serial: SerialOptions,
+
+    /// Site configuration file (TOML or JSON) with the anchor coordinates
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Estimate the range biases of a tag placed at the given surveyed position
+    #[arg(long, num_args = 3, value_names = ["X", "Y", "Z"], allow_negative_numbers = true)]
+    pub calibrate: Option<Vec<f64>>,
+
+    /// Number of range reports per anchor collected in calibration mode
+    #[arg(long, default_value_t = 200)]
+    pub calibration_samples: usize,
+
+    /// Estimate the gyroscope biases of the tags, which must be kept still
+    #[arg(long)]
+    pub calibrate_gyro: bool,
+
+    /// Number of IMU samples per tag collected in gyroscope calibration mode
+    #[arg(long, default_value_t = 5000)]
+    pub gyro_calibration_samples: usize,
+
+    /// Site configuration file written in calibration mode
+    #[arg(long, default_value = "site-calibrated.toml")]
+    pub calibration_output: PathBuf,
+
+    /// Maximum number of iterations of the localization solver
+    #[arg(long, default_value_t = 50)]
+    pub max_iterations: usize,
+
+    /// Step size below which the localization solver has converged, in meters
+    #[arg(long, default_value_t = 1e-6)]
+    pub step_tolerance: f64,
+
+    /// Standard deviation of the range noise used for the position covariance, in meters
+    #[arg(long, default_value_t = 0.1)]
+    pub range_sigma: f64,
+
+    /// Loss applied to the range residuals by the localization solver
+    #[arg(long, value_enum, default_value_t = LossFunction::Squared)]
+    pub loss: LossFunction,
+
+    /// Residual scale of the Huber and Cauchy losses, in meters
+    #[arg(long, default_value_t = 0.3)]
+    pub loss_scale: f64,
+
+    /// Reject the anchors with a larger range residual (RANSAC), in meters
+    #[arg(long)]
+    pub outlier_threshold: Option<f64>,
+
+    /// Motion model of the tag tracker
+    #[arg(long, value_enum, default_value_t = TrackingModel::Cv)]
+    pub tracking_model: TrackingModel,
+
+    /// Spectral density of the tracker process noise (acceleration, or jerk for ca)
+    #[arg(long, default_value_t = 0.5)]
+    pub process_noise: f64,
+
+    /// Ranges with a larger normalized innovation squared are rejected by the tracker
+    #[arg(long, default_value_t = 9.0)]
+    pub gate: f64,
+
+    /// Maximum frame length in bytes, longer frames are dropped
+    #[arg(long, default_value_t = stream_decoder::DEFAULT_MAX_FRAME_LENGTH)]
+    pub max_frame_length: usize,
+
+    /// Interval between decoder statistics publications, in seconds
+    #[arg(long, default_value_t = 5.0)]
+    pub stats_interval: f64,
+
+    /// Time to wait for the range reports of all the ports of a trigger, in seconds
+    #[arg(long, default_value_t = 0.1)]
+    pub sync_window: f64,
+
+    /// Maximum number of triggers waiting for range reports
+    #[arg(long, default_value_t = 64)]
+    pub sync_max_pending: usize,
+
+    /// IMU sample interval above which a gap is reported, in microseconds
+    #[arg(long, default_value_t = 1500)]
+    pub imu_gap_threshold: u64,
+}
+
+impl Options {
+    /// Loss of the localization solver
+    pub fn loss(&self) -> optimization::Loss {
+        match self.loss {
+            LossFunction::Squared => optimization::Loss::Squared,
+            LossFunction::Huber => optimization::Loss::Huber(self.loss_scale),
+            LossFunction::Cauchy => optimization::Loss::Cauchy(self.loss_scale),
+        }
+    }
+
+    /// Motion model of the tag tracker
+    pub fn motion_model(&self) -> tracking::MotionModel {
+        match self.tracking_model {
+            TrackingModel::Cv => tracking::MotionModel::ConstantVelocity,
+            TrackingModel::Ca => tracking::MotionModel::ConstantAcceleration,
+        }
+    }
+}
+
+/// Print the serial ports attached, for `list-ports`
+pub fn list_ports() {
+    match port_selector::list_ports() {
+        Ok(ports) if ports.is_empty() => println!("No serial ports found"),
+        Ok(ports) => {
+            for port in ports {
+                println!("{}", port);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 pub fn parse() -> Options {
@@ -28,3 +258,56 @@ pub fn parse() -> Options {
 
     opts
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_settings() {
+        let site = SiteConfiguration::from_toml(
+            r#"
+            anchors = []
+
+            [[ports]]
+            device = "serial=000683012345"
+
+            [[ports]]
+            device = "/dev/ttyUSB0"
+            role = { anchor = 0 }
+            baud_rate = 115200
+            flow_control = "hardware"
+            timeout_ms = 50
+            low_latency = false
+            "#,
+        )
+        .unwrap();
+
+        let mut options = SerialOptions {
+            serial_ports: Vec::new(),
+            baud_rate: None,
+            flow_control: None,
+            serial_timeout: None,
+            no_low_latency: false,
+        };
+
+        // The ports of the site, with their settings, the anchor 0 first
+        let settings = options.port_settings(&site).unwrap();
+        assert_eq!(settings.len(), 2);
+        assert_eq!(settings[0].baud_rate, 115200);
+        assert_eq!(settings[0].flow_control, FlowControl::Hardware);
+        assert_eq!(settings[0].timeout, Duration::from_millis(50));
+        assert!(!settings[0].low_latency);
+        assert_eq!(settings[1].baud_rate, serial_source::DEFAULT_BAUD_RATE);
+        assert_eq!(settings[1].flow_control, FlowControl::None);
+        assert!(settings[1].low_latency);
+
+        // The command line takes precedence
+        options.serial_ports = vec!["/dev/ttyUSB0".parse().unwrap()];
+        options.baud_rate = Some(2000000);
+        let settings = options.port_settings(&site).unwrap();
+        assert_eq!(settings.len(), 1);
+        assert_eq!(settings[0].baud_rate, 2000000);
+        assert_eq!(settings[0].timeout, Duration::from_millis(50));
+    }
+}
@@ -1,3 +1,17 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::Path,
+};
+
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    imu::ImuConfiguration, optimization::Height, port_selector::PortSelector,
+    serial_source::FlowControl,
+};
+
 /*
   Current configuration:
     - From left down corner: 5, 4, 2
@@ -28,3 +42,762 @@ pub const COORDINATES_TCR: [(f64, f64, f64); 8] = [
 ];
 
 pub const COORDINATES: [(f64, f64, f64); 8] = COORDINATES_TCR;
+
+/// Range bias subtracted when no calibration is configured, in meters
+pub const DEFAULT_RANGE_BIAS: f64 = 76.80;
+
+/// Error while loading or saving a site configuration
+#[derive(Debug)]
+pub enum ConfigurationError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    TomlSerialize(toml::ser::Error),
+    Json(serde_json::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigurationError::Io(e) => write!(f, "I/O error: {}", e),
+            ConfigurationError::Toml(e) => write!(f, "TOML error: {}", e),
+            ConfigurationError::TomlSerialize(e) => write!(f, "TOML error: {}", e),
+            ConfigurationError::Json(e) => write!(f, "JSON error: {}", e),
+            ConfigurationError::Invalid(e) => write!(f, "invalid configuration: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigurationError {}
+
+impl From<std::io::Error> for ConfigurationError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigurationError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigurationError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigurationError::Toml(e)
+    }
+}
+
+impl From<toml::ser::Error> for ConfigurationError {
+    fn from(e: toml::ser::Error) -> Self {
+        ConfigurationError::TomlSerialize(e)
+    }
+}
+
+impl From<serde_json::Error> for ConfigurationError {
+    fn from(e: serde_json::Error) -> Self {
+        ConfigurationError::Json(e)
+    }
+}
+
+/// A UWB anchor of the site
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Anchor {
+    /// Index of the anchor in `RangeReport.ranges`
+    pub id: usize,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    /// Free-form information about the anchor (location, serial number, ...)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Anchor {
+    pub fn position(&self) -> Vector3<f64> {
+        Vector3::new(self.x, self.y, self.z)
+    }
+}
+
+/// Range correction for a (tag, anchor) pair
+///
+/// The corrected range is `(range - bias) * scale`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RangeCalibration {
+    /// Tag address, the calibration applies to all tags if omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<u16>,
+    /// Anchor id
+    pub anchor: usize,
+    #[serde(default)]
+    pub bias: f64,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+}
+
+/// Per tag settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TagConfiguration {
+    /// Tag address
+    pub tag: u16,
+    /// Known height of the tag, only x and y are solved if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<f64>,
+    /// Standard deviation of `height`, which is then a soft prior, in meters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height_sigma: Option<f64>,
+    /// IMU full scales and calibration, the defaults if omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imu: Option<ImuConfiguration>,
+}
+
+impl TagConfiguration {
+    pub fn height_constraint(&self) -> Height {
+        match (self.height, self.height_sigma) {
+            (None, _) => Height::Free,
+            (Some(height), None) => Height::Fixed(height),
+            (Some(height), Some(sigma)) => Height::Prior { height, sigma },
+        }
+    }
+}
+
+/// Logical role of the device on a serial port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortRole {
+    /// Anchor id
+    Anchor(usize),
+    /// Tag address
+    Tag(u16),
+}
+
+impl fmt::Display for PortRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortRole::Anchor(id) => write!(f, "anchor {}", id),
+            PortRole::Tag(tag) => write!(f, "tag {:#06x}", tag),
+        }
+    }
+}
+
+/// A serial port of the site
+///
+/// The port of an anchor is numbered with its anchor id, the other ports take
+/// the remaining numbers in the order of the configuration.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortConfiguration {
+    /// Device path, `serial=<serial number>`, `<vid>:<pid>` or pattern
+    pub device: PortSelector,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<PortRole>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baud_rate: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flow_control: Option<FlowControl>,
+    /// Read and write timeout, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Enable the low latency mode of the USB serial adapter, on by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub low_latency: Option<bool>,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+fn default_range_bias() -> f64 {
+    DEFAULT_RANGE_BIAS
+}
+
+/// Site configuration, loaded from a TOML (or JSON) file
+///
+/// ```toml
+/// # Bias of the (tag, anchor) pairs without a calibration entry
+/// default_range_bias = 76.80
+///
+/// [[anchors]]
+/// id = 0
+/// x = -0.485
+/// y = 5.402
+/// z = 1.374
+/// metadata = { location = "north wall" }
+///
+/// [[range_calibration]]
+/// tag = 0x0134
+/// anchor = 0
+/// bias = 76.52
+/// scale = 1.0
+///
+/// # Tag on a ground robot, at a known height
+/// [[tags]]
+/// tag = 0x0134
+/// height = 0.25
+/// height_sigma = 0.02
+///
+/// [tags.imu]
+/// accel_range = 4.0
+/// gyro_range = 2000.0
+/// gyro = { bias = [0.012, -0.004, 0.001] }
+///
+/// # Serial ports, by USB serial number
+/// [[ports]]
+/// device = "serial=000683012345"
+/// role = { tag = 0x0134 }
+///
+/// [[ports]]
+/// device = "serial=000683054321"
+/// role = { anchor = 0 }
+///
+/// # Adapter without low latency mode, at its own baud rate
+/// [[ports]]
+/// device = "/dev/ttyUSB0"
+/// baud_rate = 115200
+/// flow_control = "hardware"
+/// timeout_ms = 50
+/// low_latency = false
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SiteConfiguration {
+    #[serde(default = "default_range_bias")]
+    pub default_range_bias: f64,
+    pub anchors: Vec<Anchor>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub range_calibration: Vec<RangeCalibration>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<TagConfiguration>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<PortConfiguration>,
+}
+
+impl Default for SiteConfiguration {
+    fn default() -> Self {
+        SiteConfiguration {
+            default_range_bias: DEFAULT_RANGE_BIAS,
+            anchors: Vec::new(),
+            range_calibration: Vec::new(),
+            tags: Vec::new(),
+            ports: Vec::new(),
+        }
+    }
+}
+
+impl SiteConfiguration {
+    /// Build a site configuration from the anchor coordinates, in `ranges` order
+    pub fn from_coordinates(coordinates: &[(f64, f64, f64)]) -> Self {
+        let anchors = coordinates
+            .iter()
+            .enumerate()
+            .map(|(id, &(x, y, z))| Anchor {
+                id,
+                x,
+                y,
+                z,
+                metadata: BTreeMap::new(),
+            })
+            .collect();
+
+        SiteConfiguration {
+            anchors,
+            ..Default::default()
+        }
+    }
+
+    /// Load the site configuration, JSON if the extension is `.json`, TOML otherwise
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigurationError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+
+        if path.extension().is_some_and(|ext| ext == "json") {
+            Self::from_json(&contents)
+        } else {
+            Self::from_toml(&contents)
+        }
+    }
+
+    /// Save the site configuration, JSON if the extension is `.json`, TOML otherwise
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ConfigurationError> {
+        let path = path.as_ref();
+
+        let contents = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::to_string_pretty(self)?
+        } else {
+            toml::to_string_pretty(self)?
+        };
+
+        Ok(std::fs::write(path, contents)?)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, ConfigurationError> {
+        let configuration: SiteConfiguration = toml::from_str(contents)?;
+        configuration.validate()?;
+        Ok(configuration)
+    }
+
+    pub fn from_json(contents: &str) -> Result<Self, ConfigurationError> {
+        let configuration: SiteConfiguration = serde_json::from_str(contents)?;
+        configuration.validate()?;
+        Ok(configuration)
+    }
+
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let mut ids = BTreeSet::new();
+        for anchor in self.anchors.iter() {
+            if !ids.insert(anchor.id) {
+                return Err(ConfigurationError::Invalid(format!(
+                    "duplicate anchor id {}",
+                    anchor.id
+                )));
+            }
+        }
+
+        for entry in self.range_calibration.iter() {
+            if !entry.scale.is_normal() {
+                return Err(ConfigurationError::Invalid(format!(
+                    "invalid range scale {} for anchor {}",
+                    entry.scale, entry.anchor
+                )));
+            }
+        }
+
+        let mut tags = BTreeSet::new();
+        for entry in self.tags.iter() {
+            if !tags.insert(entry.tag) {
+                return Err(ConfigurationError::Invalid(format!(
+                    "duplicate tag {:#06x}",
+                    entry.tag
+                )));
+            }
+            if entry
+                .height_sigma
+                .is_some_and(|sigma| !sigma.is_normal() || sigma < 0.0)
+            {
+                return Err(ConfigurationError::Invalid(format!(
+                    "invalid height sigma for tag {:#06x}",
+                    entry.tag
+                )));
+            }
+            if let Some(imu) = &entry.imu {
+                let scales = imu.accel.scale.iter().chain(imu.gyro.scale.iter());
+                let ranges = [imu.accel_range, imu.gyro_range];
+                if !scales.chain(ranges.iter()).all(|v| v.is_normal()) {
+                    return Err(ConfigurationError::Invalid(format!(
+                        "invalid IMU scale or range for tag {:#06x}",
+                        entry.tag
+                    )));
+                }
+            }
+        }
+
+        let mut devices = BTreeSet::new();
+        let mut roles = BTreeSet::new();
+        for entry in self.ports.iter() {
+            if !devices.insert(entry.device.to_string()) {
+                return Err(ConfigurationError::Invalid(format!(
+                    "duplicate port {}",
+                    entry.device
+                )));
+            }
+            if entry.baud_rate == Some(0) {
+                return Err(ConfigurationError::Invalid(format!(
+                    "invalid baud rate for port {}",
+                    entry.device
+                )));
+            }
+            if let Some(role) = entry.role {
+                if !roles.insert(role.to_string()) {
+                    return Err(ConfigurationError::Invalid(format!(
+                        "several ports with the role {}",
+                        role
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn anchor(&self, id: usize) -> Option<&Anchor> {
+        self.anchors.iter().find(|anchor| anchor.id == id)
+    }
+
+    /// Get the `(bias, scale)` correction for a tag and anchor
+    ///
+    /// A tag specific entry takes precedence over an entry for all tags,
+    /// which takes precedence over `default_range_bias`.
+    pub fn range_correction(&self, tag: u16, anchor: usize) -> (f64, f64) {
+        let entries = self
+            .range_calibration
+            .iter()
+            .filter(|entry| entry.anchor == anchor);
+
+        let mut correction = (self.default_range_bias, 1.0);
+        for entry in entries {
+            match entry.tag {
+                Some(entry_tag) if entry_tag == tag => return (entry.bias, entry.scale),
+                None => correction = (entry.bias, entry.scale),
+                _ => {}
+            }
+        }
+
+        correction
+    }
+
+    pub fn tag(&self, tag: u16) -> Option<&TagConfiguration> {
+        self.tags.iter().find(|entry| entry.tag == tag)
+    }
+
+    /// Settings of a tag, added if the tag is not configured
+    pub fn tag_mut(&mut self, tag: u16) -> &mut TagConfiguration {
+        let index = match self.tags.iter().position(|entry| entry.tag == tag) {
+            Some(index) => index,
+            None => {
+                self.tags.push(TagConfiguration {
+                    tag,
+                    height: None,
+                    height_sigma: None,
+                    imu: None,
+                });
+                self.tags.len() - 1
+            }
+        };
+
+        &mut self.tags[index]
+    }
+
+    /// Constraint on the height of a tag, free if the tag is not configured
+    pub fn height_constraint(&self, tag: u16) -> Height {
+        self.tag(tag)
+            .map_or(Height::Free, TagConfiguration::height_constraint)
+    }
+
+    pub fn port(&self, device: &PortSelector) -> Option<&PortConfiguration> {
+        self.ports.iter().find(|entry| entry.device == *device)
+    }
+
+    /// Role of the device of a serial port, if configured
+    pub fn port_role(&self, device: &PortSelector) -> Option<PortRole> {
+        self.port(device).and_then(|entry| entry.role)
+    }
+
+    /// Order the serial ports by the role of their device
+    ///
+    /// The port of an anchor is at the index of its anchor id, the other ports
+    /// take the remaining indices in the given order.
+    pub fn order_ports(
+        &self,
+        selectors: Vec<PortSelector>,
+    ) -> Result<Vec<PortSelector>, ConfigurationError> {
+        let count = selectors.len();
+        let mut anchors: Vec<Option<PortSelector>> = vec![None; count];
+        let mut others = Vec::new();
+
+        for selector in selectors {
+            let Some(PortRole::Anchor(id)) = self.port_role(&selector) else {
+                others.push(selector);
+                continue;
+            };
+            if id >= count {
+                return Err(ConfigurationError::Invalid(format!(
+                    "port {} of anchor {} is beyond the {} ports",
+                    selector, id, count
+                )));
+            }
+            if anchors[id].is_some() {
+                return Err(ConfigurationError::Invalid(format!(
+                    "several ports with the role {}",
+                    PortRole::Anchor(id)
+                )));
+            }
+            anchors[id] = Some(selector);
+        }
+
+        // The other ports fill the indices left free by the anchors
+        let mut others = others.into_iter();
+        Ok(anchors
+            .into_iter()
+            .flat_map(|slot| slot.or_else(|| others.next()))
+            .collect())
+    }
+
+    /// IMU settings of a tag, the defaults if the tag is not configured
+    pub fn imu_configuration(&self, tag: u16) -> ImuConfiguration {
+        self.tag(tag)
+            .and_then(|entry| entry.imu)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_site_configuration() {
+        let configuration = SiteConfiguration::from_toml(
+            r#"
+            [[anchors]]
+            id = 0
+            x = 1.0
+            y = 2.0
+            z = 3.0
+            metadata = { location = "north wall" }
+
+            [[anchors]]
+            id = 3
+            x = -1.0
+            y = 0.5
+            z = 1.3
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(configuration.anchors.len(), 2);
+        assert_eq!(
+            configuration.anchor(0).unwrap().metadata["location"],
+            "north wall"
+        );
+        assert_eq!(
+            configuration.anchor(3).unwrap().position(),
+            Vector3::new(-1.0, 0.5, 1.3)
+        );
+        assert!(configuration.anchor(1).is_none());
+
+        // The TOML output can be read back
+        let serialized = toml::to_string_pretty(&configuration).unwrap();
+        assert_eq!(
+            SiteConfiguration::from_toml(&serialized).unwrap(),
+            configuration
+        );
+    }
+
+    #[test]
+    fn test_duplicate_anchor_ids() {
+        let result = SiteConfiguration::from_json(
+            r#"{"anchors": [
+                {"id": 1, "x": 0.0, "y": 0.0, "z": 0.0},
+                {"id": 1, "x": 1.0, "y": 0.0, "z": 0.0}
+            ]}"#,
+        );
+
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_bundled_site_configuration() {
+        let configuration =
+            SiteConfiguration::load(concat!(env!("CARGO_MANIFEST_DIR"), "/config/tcr.toml"))
+                .unwrap();
+
+        assert_eq!(
+            configuration,
+            SiteConfiguration::from_coordinates(&COORDINATES_TCR)
+        );
+    }
+
+    #[test]
+    fn test_range_correction() {
+        let configuration = SiteConfiguration::from_toml(
+            r#"
+            default_range_bias = 70.0
+
+            [[anchors]]
+            id = 0
+            x = 0.0
+            y = 0.0
+            z = 0.0
+
+            [[range_calibration]]
+            anchor = 0
+            bias = 71.0
+
+            [[range_calibration]]
+            tag = 0x0134
+            anchor = 0
+            bias = 72.0
+            scale = 0.99
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(configuration.range_correction(0x0134, 0), (72.0, 0.99));
+        assert_eq!(configuration.range_correction(0x0135, 0), (71.0, 1.0));
+        assert_eq!(configuration.range_correction(0x0134, 1), (70.0, 1.0));
+    }
+
+    #[test]
+    fn test_height_constraint() {
+        let configuration = SiteConfiguration::from_toml(
+            r#"
+            anchors = []
+
+            [[tags]]
+            tag = 1
+            height = 0.25
+
+            [[tags]]
+            tag = 2
+            height = 0.5
+            height_sigma = 0.1
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(configuration.height_constraint(1), Height::Fixed(0.25));
+        assert_eq!(
+            configuration.height_constraint(2),
+            Height::Prior {
+                height: 0.5,
+                sigma: 0.1
+            }
+        );
+        assert_eq!(configuration.height_constraint(3), Height::Free);
+        assert_eq!(
+            configuration.imu_configuration(1),
+            ImuConfiguration::default()
+        );
+
+        let result = SiteConfiguration::from_toml(
+            r#"
+            anchors = []
+
+            [[tags]]
+            tag = 1
+            height = 0.25
+            height_sigma = 0.0
+            "#,
+        );
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_imu_configuration() {
+        let mut configuration = SiteConfiguration::from_toml(
+            r#"
+            anchors = []
+
+            [[tags]]
+            tag = 1
+
+            [tags.imu]
+            gyro_range = 500.0
+            accel = { bias = [0.1, 0.0, -0.1], scale = [1.0, 1.01, 0.99] }
+            "#,
+        )
+        .unwrap();
+
+        let imu = configuration.imu_configuration(1);
+        assert_eq!(imu.accel_range, 4.0);
+        assert_eq!(imu.gyro_range, 500.0);
+        assert_eq!(imu.accel.scale, [1.0, 1.01, 0.99]);
+        assert_eq!(imu.gyro, Default::default());
+
+        // Calibrating a new tag adds it
+        configuration.tag_mut(2).imu = Some(ImuConfiguration::default());
+        assert_eq!(configuration.tags.len(), 2);
+        configuration.tag_mut(1).height = Some(0.5);
+        assert_eq!(configuration.tags.len(), 2);
+
+        let serialized = toml::to_string_pretty(&configuration).unwrap();
+        assert_eq!(
+            SiteConfiguration::from_toml(&serialized).unwrap(),
+            configuration
+        );
+    }
+
+    #[test]
+    fn test_port_roles() {
+        let configuration = SiteConfiguration::from_toml(
+            r#"
+            anchors = []
+
+            [[ports]]
+            device = "serial=000683012345"
+            role = { tag = 0x0134 }
+
+            [[ports]]
+            device = "1366:1015"
+            role = { anchor = 2 }
+
+            [[ports]]
+            device = "/dev/ttyUSB0"
+            baud_rate = 115200
+            flow_control = "hardware"
+            low_latency = false
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(configuration.ports.len(), 3);
+        assert_eq!(
+            configuration.port_role(&PortSelector::SerialNumber("000683012345".into())),
+            Some(PortRole::Tag(0x0134))
+        );
+        assert_eq!(
+            configuration.port_role(&PortSelector::VidPid(0x1366, 0x1015)),
+            Some(PortRole::Anchor(2))
+        );
+        let usb = PortSelector::Path("/dev/ttyUSB0".into());
+        assert_eq!(configuration.port_role(&usb), None);
+        let port = configuration.port(&usb).unwrap();
+        assert_eq!(port.baud_rate, Some(115200));
+        assert_eq!(port.flow_control, Some(FlowControl::Hardware));
+        assert_eq!(port.timeout_ms, None);
+        assert_eq!(port.low_latency, Some(false));
+
+        let serialized = toml::to_string_pretty(&configuration).unwrap();
+        assert_eq!(
+            SiteConfiguration::from_toml(&serialized).unwrap(),
+            configuration
+        );
+
+        let result = SiteConfiguration::from_toml(
+            r#"
+            anchors = []
+
+            [[ports]]
+            device = "serial=1"
+            role = { tag = 1 }
+
+            [[ports]]
+            device = "serial=2"
+            role = { tag = 1 }
+            "#,
+        );
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_order_ports() {
+        let configuration = SiteConfiguration::from_toml(
+            r#"
+            anchors = []
+
+            [[ports]]
+            device = "serial=1"
+            role = { tag = 0x0134 }
+
+            [[ports]]
+            device = "serial=2"
+            role = { anchor = 1 }
+
+            [[ports]]
+            device = "serial=3"
+            role = { anchor = 0 }
+            "#,
+        )
+        .unwrap();
+        let serial = |number: &str| PortSelector::SerialNumber(number.into());
+
+        // Anchors at the index of their id, the other ports in order
+        let ordered = configuration
+            .order_ports(vec![serial("1"), serial("4"), serial("2"), serial("3")])
+            .unwrap();
+        assert_eq!(
+            ordered,
+            vec![serial("3"), serial("2"), serial("1"), serial("4")]
+        );
+
+        // Not enough ports for the anchor ids
+        let result = configuration.order_ports(vec![serial("2")]);
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+
+        // The same device given twice
+        let result = configuration.order_ports(vec![serial("2"), serial("2"), serial("3")]);
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+    }
+}
@@ -0,0 +1,279 @@
+// Timing diagnostics
+//
+// Per tag monitoring of the IMU sample timestamps: rate, interval histogram,
+// gaps, out-of-order samples and timestamp resets (tag reboot).
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// Options of the IMU timing monitor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImuTimingOptions {
+    /// Intervals longer than this are gaps, in microseconds
+    pub gap_threshold_us: u64,
+    /// Timestamps going back by more than this are a reset, in microseconds
+    pub reset_threshold_us: u64,
+    /// Width of the interval histogram bins, in microseconds
+    pub bin_width_us: u64,
+    /// Number of histogram bins, the last one counts all the longer intervals
+    pub bins: usize,
+}
+
+impl Default for ImuTimingOptions {
+    fn default() -> Self {
+        ImuTimingOptions {
+            gap_threshold_us: 1500,
+            reset_threshold_us: 1_000_000,
+            bin_width_us: 100,
+            bins: 21,
+        }
+    }
+}
+
+/// Timing of an IMU sample relative to the previous one of the same tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImuTimingEvent {
+    /// First sample of the tag
+    First,
+    /// Regular interval, in microseconds
+    Interval(u64),
+    /// Interval longer than the gap threshold, in microseconds
+    Gap(u64),
+    /// Same timestamp as the previous sample
+    Duplicate,
+    /// Timestamp before the previous one, by the given microseconds
+    OutOfOrder(u64),
+    /// Timestamp far before the previous one, the tag restarted
+    Reset,
+}
+
+/// IMU timing of a tag since the previous report, as published on `imu_timing`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImuTimingReport {
+    pub tag_addr: u16,
+    /// Number of samples
+    pub samples: u64,
+    /// Sample rate, in Hz
+    pub rate_hz: f64,
+    /// Mean interval between samples, in microseconds
+    pub mean_interval_us: f64,
+    /// Standard deviation of the intervals, gaps excluded, in microseconds
+    pub jitter_us: f64,
+    pub min_interval_us: Option<u64>,
+    pub max_interval_us: Option<u64>,
+    /// Width of the histogram bins, in microseconds
+    pub bin_width_us: u64,
+    /// Interval histogram, the last bin counts all the longer intervals
+    pub histogram: Vec<u64>,
+    pub gaps: u64,
+    /// Estimated number of samples lost in the gaps
+    pub missing_samples: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    pub resets: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TagTiming {
+    last_ts: Option<u64>,
+    samples: u64,
+    intervals: u64,
+    interval_sum: u64,
+    regular_intervals: u64,
+    regular_sum: f64,
+    regular_sum_squares: f64,
+    min_interval: Option<u64>,
+    max_interval: Option<u64>,
+    histogram: Vec<u64>,
+    gaps: u64,
+    missing_samples: u64,
+    duplicates: u64,
+    out_of_order: u64,
+    resets: u64,
+}
+
+/// Monitor of the IMU timestamps of all the tags
+pub struct ImuTimingMonitor {
+    options: ImuTimingOptions,
+    tags: BTreeMap<u16, TagTiming>,
+}
+
+impl ImuTimingMonitor {
+    pub fn new(options: ImuTimingOptions) -> Self {
+        ImuTimingMonitor {
+            options,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Add the timestamp of a sample, in microseconds
+    pub fn add(&mut self, tag: u16, system_ts: u64) -> ImuTimingEvent {
+        let options = &self.options;
+        let timing = self.tags.entry(tag).or_default();
+        if timing.histogram.len() != options.bins {
+            timing.histogram = vec![0; options.bins];
+        }
+        timing.samples += 1;
+
+        let Some(last_ts) = timing.last_ts else {
+            timing.last_ts = Some(system_ts);
+            return ImuTimingEvent::First;
+        };
+
+        if system_ts == last_ts {
+            timing.duplicates += 1;
+            return ImuTimingEvent::Duplicate;
+        }
+
+        if system_ts < last_ts {
+            let back = last_ts - system_ts;
+            if back > options.reset_threshold_us {
+                timing.resets += 1;
+                timing.last_ts = Some(system_ts);
+                return ImuTimingEvent::Reset;
+            }
+            // Keep the latest timestamp as the reference
+            timing.out_of_order += 1;
+            return ImuTimingEvent::OutOfOrder(back);
+        }
+
+        let interval = system_ts - last_ts;
+        timing.last_ts = Some(system_ts);
+        timing.intervals += 1;
+        timing.interval_sum += interval;
+        timing.min_interval = Some(timing.min_interval.map_or(interval, |m| m.min(interval)));
+        timing.max_interval = Some(timing.max_interval.map_or(interval, |m| m.max(interval)));
+
+        let bin = ((interval / options.bin_width_us.max(1)) as usize).min(options.bins - 1);
+        timing.histogram[bin] += 1;
+
+        if interval > options.gap_threshold_us {
+            timing.gaps += 1;
+            // Estimate the nominal interval from the regular ones, if any
+            if timing.regular_intervals > 0 {
+                let nominal = timing.regular_sum / timing.regular_intervals as f64;
+                timing.missing_samples += ((interval as f64 / nominal).round() as u64).max(1) - 1;
+            }
+            return ImuTimingEvent::Gap(interval);
+        }
+
+        timing.regular_intervals += 1;
+        timing.regular_sum += interval as f64;
+        timing.regular_sum_squares += (interval as f64).powi(2);
+        ImuTimingEvent::Interval(interval)
+    }
+
+    /// Report the timing of every tag, and start new statistics
+    ///
+    /// The last timestamp of the tags is kept, so that the intervals are continuous.
+    pub fn take_reports(&mut self) -> Vec<ImuTimingReport> {
+        let options = &self.options;
+        let mut reports = Vec::new();
+
+        for (&tag, timing) in self.tags.iter_mut() {
+            let mean_interval_us = if timing.intervals > 0 {
+                timing.interval_sum as f64 / timing.intervals as f64
+            } else {
+                0.0
+            };
+            let jitter_us = if timing.regular_intervals > 0 {
+                let n = timing.regular_intervals as f64;
+                let mean = timing.regular_sum / n;
+                (timing.regular_sum_squares / n - mean * mean)
+                    .max(0.0)
+                    .sqrt()
+            } else {
+                0.0
+            };
+
+            reports.push(ImuTimingReport {
+                tag_addr: tag,
+                samples: timing.samples,
+                rate_hz: if mean_interval_us > 0.0 {
+                    1e6 / mean_interval_us
+                } else {
+                    0.0
+                },
+                mean_interval_us,
+                jitter_us,
+                min_interval_us: timing.min_interval,
+                max_interval_us: timing.max_interval,
+                bin_width_us: options.bin_width_us,
+                histogram: timing.histogram.clone(),
+                gaps: timing.gaps,
+                missing_samples: timing.missing_samples,
+                duplicates: timing.duplicates,
+                out_of_order: timing.out_of_order,
+                resets: timing.resets,
+            });
+
+            *timing = TagTiming {
+                last_ts: timing.last_ts,
+                histogram: vec![0; options.bins],
+                ..Default::default()
+            };
+        }
+
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_imu_timing() {
+        let mut monitor = ImuTimingMonitor::new(ImuTimingOptions::default());
+
+        assert_eq!(monitor.add(1, 10_000_000), ImuTimingEvent::First);
+        let mut ts = 10_000_000;
+        for i in 0..100 {
+            // 1 kHz with a small jitter
+            ts += if i % 2 == 0 { 950 } else { 1050 };
+            assert!(matches!(monitor.add(1, ts), ImuTimingEvent::Interval(_)));
+        }
+
+        // Another tag does not disturb the first one
+        monitor.add(2, 5);
+        monitor.add(2, 1005);
+
+        // Three samples lost
+        ts += 4000;
+        assert_eq!(monitor.add(1, ts), ImuTimingEvent::Gap(4000));
+        assert_eq!(monitor.add(1, ts), ImuTimingEvent::Duplicate);
+        assert_eq!(monitor.add(1, ts - 500), ImuTimingEvent::OutOfOrder(500));
+        ts += 1000;
+        assert_eq!(monitor.add(1, ts), ImuTimingEvent::Interval(1000));
+
+        // Reboot of the tag, no underflow
+        assert_eq!(monitor.add(1, 100), ImuTimingEvent::Reset);
+        assert_eq!(monitor.add(1, 1100), ImuTimingEvent::Interval(1000));
+
+        let reports = monitor.take_reports();
+        assert_eq!(reports.len(), 2);
+        let report = &reports[0];
+        assert_eq!(report.tag_addr, 1);
+        assert_eq!(report.samples, 107);
+        assert_eq!(report.gaps, 1);
+        assert_eq!(report.missing_samples, 3);
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(report.out_of_order, 1);
+        assert_eq!(report.resets, 1);
+        assert_eq!(report.min_interval_us, Some(950));
+        assert_eq!(report.max_interval_us, Some(4000));
+        assert!((report.jitter_us - 50.0).abs() < 1.0);
+        assert_eq!(report.histogram[9], 50);
+        assert_eq!(report.histogram[10], 52);
+        assert_eq!(report.histogram[20], 1);
+        assert_eq!(report.histogram.iter().sum::<u64>(), 103);
+        assert_eq!(reports[1].rate_hz, 1000.0);
+
+        // The statistics restart, the intervals continue
+        assert_eq!(monitor.add(1, 2100), ImuTimingEvent::Interval(1000));
+        let report = &monitor.take_reports()[0];
+        assert_eq!(report.samples, 1);
+        assert_eq!(report.rate_hz, 1000.0);
+    }
+}
@@ -0,0 +1,474 @@
+// UWB and IMU fusion
+//
+// Error-state extended Kalman filter per tag: the nominal position, velocity,
+// orientation and IMU biases are propagated with the accelerometer and gyroscope
+// samples, and the error state is corrected with the UWB ranges to the anchors.
+// The pose is available at the IMU rate.
+
+use std::collections::HashMap;
+
+use nalgebra::{SMatrix, SVector, UnitQuaternion, Vector3};
+use serde::Serialize;
+
+use crate::{
+    configuration::Anchor,
+    imu::{ImuSample, GRAVITY},
+    optimization::{initial_covariance, Fix},
+};
+
+/// Error state: position, velocity, orientation, accelerometer and gyroscope biases
+const N: usize = 15;
+
+type Covariance = SMatrix<f64, N, N>;
+type ErrorVector = SVector<f64, N>;
+
+/// Options of the UWB and IMU fusion
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FusionOptions {
+    /// Accelerometer noise density, in m/s²/√Hz
+    pub accel_noise: f64,
+    /// Gyroscope noise density, in rad/s/√Hz
+    pub gyro_noise: f64,
+    /// Accelerometer bias random walk, in m/s³/√Hz
+    pub accel_bias_noise: f64,
+    /// Gyroscope bias random walk, in rad/s²/√Hz
+    pub gyro_bias_noise: f64,
+    /// Standard deviation of the range noise, in meters
+    pub range_sigma: f64,
+    /// Ranges with a larger normalized innovation squared are rejected
+    pub gate: f64,
+    /// Initial standard deviation of the position when the fix covariance is not
+    /// finite, in meters
+    pub position_sigma: f64,
+    /// Initial standard deviation of the velocity, in m/s
+    pub velocity_sigma: f64,
+    /// Initial standard deviation of the heading, in radians
+    pub heading_sigma: f64,
+    /// Ranges further than this from the filter time are dropped, in seconds
+    pub max_range_delay: f64,
+    /// A filter is restarted when no IMU sample came for this time, in seconds
+    pub timeout: f64,
+}
+
+impl Default for FusionOptions {
+    fn default() -> Self {
+        FusionOptions {
+            accel_noise: 0.05,
+            gyro_noise: 0.005,
+            accel_bias_noise: 0.001,
+            gyro_bias_noise: 0.0001,
+            range_sigma: 0.1,
+            gate: 9.0,
+            position_sigma: 1.0,
+            velocity_sigma: 1.0,
+            heading_sigma: 1.0,
+            max_range_delay: 0.05,
+            timeout: 0.5,
+        }
+    }
+}
+
+/// Pose of a tag, as published on `poses`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PoseEstimate {
+    pub tag_addr: u16,
+    /// Time of the estimate, in seconds
+    pub time: f64,
+    pub position: [f64; 3],
+    pub velocity: [f64; 3],
+    /// Orientation of the body in the site frame, as a `[w, x, y, z]` quaternion
+    pub orientation: [f64; 4],
+    /// Angular rate in the body frame, bias corrected, in rad/s
+    pub angular_rate: [f64; 3],
+    /// Position covariance, in m²
+    pub position_covariance: [[f64; 3]; 3],
+    /// Orientation (rotation vector) covariance, in rad²
+    pub orientation_covariance: [[f64; 3]; 3],
+}
+
+/// Error-state EKF of a single tag
+#[derive(Debug, Clone)]
+pub struct InertialFilter {
+    pub position: Vector3<f64>,
+    pub velocity: Vector3<f64>,
+    /// Rotation from the body to the site frame
+    pub orientation: UnitQuaternion<f64>,
+    pub accel_bias: Vector3<f64>,
+    pub gyro_bias: Vector3<f64>,
+    covariance: Covariance,
+    time: f64,
+    /// Last bias corrected angular rate
+    angular_rate: Vector3<f64>,
+}
+
+impl InertialFilter {
+    /// Start at a localized position, leveled with the IMU sample
+    ///
+    /// The roll and pitch are given by the gravity in `imu`, the heading is unknown.
+    /// A non-finite fix covariance is replaced by `options.position_sigma`.
+    pub fn new(fix: &Fix, imu: &ImuSample, options: &FusionOptions) -> Self {
+        let orientation = UnitQuaternion::rotation_between(&imu.accel(), &Vector3::z())
+            .unwrap_or_else(UnitQuaternion::identity);
+
+        let mut covariance = Covariance::zeros();
+        covariance
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&initial_covariance(&fix.covariance, options.position_sigma));
+        for i in 3..6 {
+            covariance[(i, i)] = options.velocity_sigma.powi(2);
+        }
+        covariance[(6, 6)] = 0.05f64.powi(2);
+        covariance[(7, 7)] = 0.05f64.powi(2);
+        covariance[(8, 8)] = options.heading_sigma.powi(2);
+        for i in 9..12 {
+            covariance[(i, i)] = 0.1f64.powi(2);
+        }
+        for i in 12..15 {
+            covariance[(i, i)] = 0.01f64.powi(2);
+        }
+
+        InertialFilter {
+            position: fix.solution.position,
+            velocity: Vector3::zeros(),
+            orientation,
+            accel_bias: Vector3::zeros(),
+            gyro_bias: Vector3::zeros(),
+            covariance,
+            time: imu.time(),
+            angular_rate: imu.gyro(),
+        }
+    }
+
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Propagate the state with an IMU sample
+    pub fn propagate(&mut self, imu: &ImuSample, options: &FusionOptions) {
+        let dt = imu.time() - self.time;
+        if dt <= 0.0 {
+            return;
+        }
+
+        let rotation = self.orientation.to_rotation_matrix().into_inner();
+        let accel = imu.accel() - self.accel_bias;
+        let gyro = imu.gyro() - self.gyro_bias;
+        let world_accel = rotation * accel - Vector3::new(0.0, 0.0, GRAVITY);
+
+        // Nominal state
+        self.position += self.velocity * dt + world_accel * (0.5 * dt * dt);
+        self.velocity += world_accel * dt;
+        self.orientation *= UnitQuaternion::from_scaled_axis(gyro * dt);
+        self.angular_rate = gyro;
+
+        // Error state transition, first order
+        let mut transition = Covariance::identity();
+        transition
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(SMatrix::<f64, 3, 3>::identity() * dt));
+        transition
+            .fixed_view_mut::<3, 3>(3, 6)
+            .copy_from(&(-rotation * accel.cross_matrix() * dt));
+        transition
+            .fixed_view_mut::<3, 3>(3, 9)
+            .copy_from(&(-rotation * dt));
+        transition
+            .fixed_view_mut::<3, 3>(6, 6)
+            .copy_from(&(SMatrix::<f64, 3, 3>::identity() - gyro.cross_matrix() * dt));
+        transition
+            .fixed_view_mut::<3, 3>(6, 12)
+            .copy_from(&(-SMatrix::<f64, 3, 3>::identity() * dt));
+
+        let mut noise = Covariance::zeros();
+        let densities = [
+            (3, options.accel_noise),
+            (6, options.gyro_noise),
+            (9, options.accel_bias_noise),
+            (12, options.gyro_bias_noise),
+        ];
+        for (offset, density) in densities {
+            for i in offset..offset + 3 {
+                noise[(i, i)] = density.powi(2) * dt;
+            }
+        }
+
+        self.covariance = transition * self.covariance * transition.transpose() + noise;
+        self.time = imu.time();
+    }
+
+    /// Correct the state with the ranges to the anchors, indexed by anchor id
+    ///
+    /// Returns the ids of the anchors gated out.
+    pub fn update_ranges(
+        &mut self,
+        anchors: &[Anchor],
+        ranges: &[f64],
+        options: &FusionOptions,
+    ) -> Vec<usize> {
+        let mut rejected = Vec::new();
+
+        for anchor in anchors {
+            let Some(&range) = ranges.get(anchor.id) else {
+                continue;
+            };
+            if !range.is_normal() {
+                continue;
+            }
+
+            let diff = self.position - anchor.position();
+            let predicted = diff.norm();
+            if predicted < 1e-9 {
+                continue;
+            }
+
+            let mut jacobian = ErrorVector::zeros();
+            jacobian
+                .fixed_rows_mut::<3>(0)
+                .copy_from(&(diff / predicted));
+
+            let ph = self.covariance * jacobian;
+            let s = jacobian.dot(&ph) + options.range_sigma.powi(2);
+            let innovation = range - predicted;
+            if innovation.powi(2) / s > options.gate {
+                rejected.push(anchor.id);
+                continue;
+            }
+
+            let gain = ph / s;
+            self.inject(&(gain * innovation));
+            self.covariance -= gain * gain.transpose() * s;
+            self.covariance = (self.covariance + self.covariance.transpose()) * 0.5;
+        }
+
+        rejected
+    }
+
+    /// Add an error state estimate to the nominal state
+    fn inject(&mut self, error: &ErrorVector) {
+        self.position += error.fixed_rows::<3>(0);
+        self.velocity += error.fixed_rows::<3>(3);
+        let rotation: Vector3<f64> = error.fixed_rows::<3>(6).into();
+        self.orientation *= UnitQuaternion::from_scaled_axis(rotation);
+        self.accel_bias += error.fixed_rows::<3>(9);
+        self.gyro_bias += error.fixed_rows::<3>(12);
+    }
+
+    pub fn estimate(&self, tag_addr: u16) -> PoseEstimate {
+        let q = self.orientation.quaternion();
+
+        PoseEstimate {
+            tag_addr,
+            time: self.time,
+            position: self.position.into(),
+            velocity: self.velocity.into(),
+            orientation: [q.w, q.i, q.j, q.k],
+            angular_rate: self.angular_rate.into(),
+            position_covariance: self.covariance.fixed_view::<3, 3>(0, 0).into(),
+            orientation_covariance: self.covariance.fixed_view::<3, 3>(6, 6).into(),
+        }
+    }
+}
+
+/// UWB and IMU fusion of all the tags
+pub struct Fusion {
+    options: FusionOptions,
+    filters: HashMap<u16, InertialFilter>,
+    /// Latest IMU sample of the tags without a filter yet
+    pending: HashMap<u16, ImuSample>,
+}
+
+impl Fusion {
+    pub fn new(options: FusionOptions) -> Self {
+        Fusion {
+            options,
+            filters: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn filter(&self, tag: u16) -> Option<&InertialFilter> {
+        self.filters.get(&tag)
+    }
+
+    /// Propagate the filter of a tag with a calibrated IMU sample
+    ///
+    /// Returns the pose once the filter is started.
+    pub fn propagate(&mut self, imu: &ImuSample) -> Option<PoseEstimate> {
+        let options = &self.options;
+        let tag = imu.tag_addr;
+
+        let stale = self.filters.get(&tag).is_some_and(|filter| {
+            imu.time() < filter.time || imu.time() - filter.time > options.timeout
+        });
+        if stale {
+            self.filters.remove(&tag);
+        }
+
+        let Some(filter) = self.filters.get_mut(&tag) else {
+            self.pending.insert(tag, *imu);
+            return None;
+        };
+
+        filter.propagate(imu, options);
+        Some(filter.estimate(tag))
+    }
+
+    /// Correct the filter of a tag with its ranges at `time`, in seconds
+    ///
+    /// The filter is started from `fix`, the localized position of the same
+    /// ranges, once an IMU sample was received. Returns the ids of the anchors
+    /// gated out.
+    pub fn update_ranges(
+        &mut self,
+        tag: u16,
+        time: f64,
+        anchors: &[Anchor],
+        ranges: &[f64],
+        fix: Option<&Fix>,
+    ) -> Vec<usize> {
+        let options = &self.options;
+
+        let Some(filter) = self.filters.get_mut(&tag) else {
+            if let (Some(fix), Some(imu)) = (fix, self.pending.get(&tag)) {
+                let filter = InertialFilter::new(fix, imu, options);
+                self.filters.insert(tag, filter);
+                self.pending.remove(&tag);
+            }
+            return Vec::new();
+        };
+
+        // The ranges are applied at the filter time, which is only valid shortly
+        if (time - filter.time).abs() > options.max_range_delay {
+            return Vec::new();
+        }
+
+        filter.update_ranges(anchors, ranges, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Matrix3;
+
+    use crate::{
+        configuration::SiteConfiguration,
+        optimization::{
+            self,
+            fixtures::{ranges, site},
+        },
+    };
+
+    fn start(site: &SiteConfiguration, position: Vector3<f64>, imu: ImuSample) -> Fusion {
+        let mut fusion = Fusion::new(FusionOptions::default());
+        let ranges = ranges(site, position);
+        let fix = optimization::localize_point(&site.anchors, &ranges, None, &Default::default())
+            .unwrap();
+
+        assert!(fusion.propagate(&imu).is_none());
+        fusion.update_ranges(1, imu.time(), &site.anchors, &ranges, Some(&fix));
+        assert!(fusion.filter(1).is_some());
+        fusion
+    }
+
+    #[test]
+    fn test_fusion_static() {
+        let site = site();
+        let position = Vector3::new(2.0, 3.0, 1.0);
+        // Tilted about x, at rest
+        let tilt = UnitQuaternion::from_euler_angles(0.2, 0.0, 0.0);
+        let accel = tilt.inverse() * Vector3::new(0.0, 0.0, GRAVITY);
+        let imu = |ms: u64| ImuSample {
+            tag_addr: 1,
+            system_ts: ms * 1000,
+            accel: accel.into(),
+            gyro: [0.0; 3],
+        };
+
+        let mut fusion = start(&site, position, imu(0));
+        let ranges = ranges(&site, position);
+
+        for k in 1..=2000 {
+            let time = imu(k).time();
+            let pose = fusion.propagate(&imu(k)).unwrap();
+            assert_eq!(pose.time, time);
+            if k % 100 == 0 {
+                let rejected = fusion.update_ranges(1, time, &site.anchors, &ranges, None);
+                assert!(rejected.is_empty());
+            }
+        }
+
+        let filter = fusion.filter(1).unwrap();
+        assert!((filter.position - position).norm() < 0.01);
+        assert!(filter.velocity.norm() < 0.01);
+        let (roll, pitch, _) = filter.orientation.euler_angles();
+        assert!((roll - 0.2).abs() < 1e-3);
+        assert!(pitch.abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_filter_infinite_covariance() {
+        let site = site();
+        let options = FusionOptions::default();
+        let position = Vector3::new(2.0, 3.0, 1.0);
+        let ranges = ranges(&site, position);
+        let mut fix =
+            optimization::localize_point(&site.anchors, &ranges, None, &Default::default())
+                .unwrap();
+        fix.covariance = Matrix3::from_element(f64::INFINITY);
+        let imu = ImuSample {
+            tag_addr: 1,
+            system_ts: 0,
+            accel: [0.0, 0.0, GRAVITY],
+            gyro: [0.0; 3],
+        };
+
+        let mut filter = InertialFilter::new(&fix, &imu, &options);
+        assert_eq!(
+            filter.covariance.fixed_view::<3, 3>(0, 0),
+            Matrix3::identity()
+        );
+
+        assert!(filter
+            .update_ranges(&site.anchors, &ranges, &options)
+            .is_empty());
+        assert!(filter.covariance.iter().all(|v| v.is_finite()));
+        assert!((filter.position - position).norm() < 0.05);
+    }
+
+    #[test]
+    fn test_fusion_motion() {
+        let site = site();
+        let start_position = Vector3::new(1.0, 1.0, 1.0);
+        // Constant yaw rate and constant velocity in the site frame
+        let yaw_rate = 0.5;
+        let velocity = Vector3::new(0.4, 0.2, 0.0);
+        let imu = |ms: u64| ImuSample {
+            tag_addr: 1,
+            system_ts: ms * 1000,
+            accel: [0.0, 0.0, GRAVITY],
+            gyro: [0.0, 0.0, yaw_rate],
+        };
+
+        let mut fusion = start(&site, start_position, imu(0));
+        let (_, _, initial_yaw) = fusion.filter(1).unwrap().orientation.euler_angles();
+
+        for k in 1..=5000 {
+            let time = imu(k).time();
+            fusion.propagate(&imu(k)).unwrap();
+            if k % 100 == 0 {
+                let ranges = ranges(&site, start_position + velocity * time);
+                fusion.update_ranges(1, time, &site.anchors, &ranges, None);
+            }
+        }
+
+        let filter = fusion.filter(1).unwrap();
+        assert!((filter.position - (start_position + velocity * 5.0)).norm() < 0.05);
+        assert!((filter.velocity - velocity).norm() < 0.05);
+
+        // The heading is not observable without acceleration, only its rate
+        let (_, _, yaw) = filter.orientation.euler_angles();
+        let turned = (yaw - initial_yaw).rem_euclid(std::f64::consts::TAU);
+        assert!((turned - yaw_rate * 5.0).abs() < 1e-2);
+    }
+}
@@ -0,0 +1,330 @@
+// IMU samples
+//
+// Conversion of the raw `ImuReport` register values to physical units, with the
+// per-axis calibration of the tag, and the static gyroscope bias calibration.
+
+use std::{collections::BTreeMap, fmt};
+
+use nalgebra::{Matrix3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::proto::ImuReport;
+
+/// Standard gravity, in m/s²
+pub const GRAVITY: f64 = 9.80665;
+
+/// Resolution of the IMU registers, in bits
+const REGISTER_BITS: i32 = 16;
+
+/// IMU sample in physical units, as published on `imu`
+#[derive(Default, Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct ImuSample {
+    pub tag_addr: u16,
+    /// Tag system timestamp, in microseconds
+    pub system_ts: u64,
+    /// Specific force, in m/s²
+    pub accel: [f64; 3],
+    /// Angular rate, in rad/s
+    pub gyro: [f64; 3],
+}
+
+impl ImuSample {
+    /// Time of the sample, in seconds
+    pub fn time(&self) -> f64 {
+        self.system_ts as f64 * 1e-6
+    }
+
+    pub fn accel(&self) -> Vector3<f64> {
+        Vector3::from(self.accel)
+    }
+
+    pub fn gyro(&self) -> Vector3<f64> {
+        Vector3::from(self.gyro)
+    }
+}
+
+/// Per-axis calibration of a 3-axis sensor
+///
+/// The calibrated value is `misalignment * (scale ∘ (measured - bias))`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SensorCalibration {
+    #[serde(default)]
+    pub bias: [f64; 3],
+    #[serde(default = "default_scale")]
+    pub scale: [f64; 3],
+    /// Rows of the misalignment matrix
+    #[serde(default = "default_misalignment")]
+    pub misalignment: [[f64; 3]; 3],
+}
+
+fn default_scale() -> [f64; 3] {
+    [1.0; 3]
+}
+
+fn default_misalignment() -> [[f64; 3]; 3] {
+    [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+}
+
+impl Default for SensorCalibration {
+    fn default() -> Self {
+        SensorCalibration {
+            bias: [0.0; 3],
+            scale: default_scale(),
+            misalignment: default_misalignment(),
+        }
+    }
+}
+
+impl SensorCalibration {
+    pub fn apply(&self, measured: [f64; 3]) -> [f64; 3] {
+        let scaled = (Vector3::from(measured) - Vector3::from(self.bias))
+            .component_mul(&Vector3::from(self.scale));
+        let misalignment = Matrix3::from_fn(|i, j| self.misalignment[i][j]);
+
+        (misalignment * scaled).into()
+    }
+}
+
+/// IMU settings of a tag
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ImuConfiguration {
+    /// Accelerometer full scale, in ±g
+    #[serde(default = "default_accel_range")]
+    pub accel_range: f64,
+    /// Gyroscope full scale, in ±°/s
+    #[serde(default = "default_gyro_range")]
+    pub gyro_range: f64,
+    #[serde(default)]
+    pub accel: SensorCalibration,
+    #[serde(default)]
+    pub gyro: SensorCalibration,
+}
+
+fn default_accel_range() -> f64 {
+    4.0
+}
+
+fn default_gyro_range() -> f64 {
+    2000.0
+}
+
+impl Default for ImuConfiguration {
+    fn default() -> Self {
+        ImuConfiguration {
+            accel_range: default_accel_range(),
+            gyro_range: default_gyro_range(),
+            accel: SensorCalibration::default(),
+            gyro: SensorCalibration::default(),
+        }
+    }
+}
+
+/// Read a register value as a signed count, whatever the sign extension
+fn signed_count(raw: u32) -> f64 {
+    raw as u16 as i16 as f64
+}
+
+impl ImuConfiguration {
+    /// Convert a raw report to physical units, without calibration
+    pub fn to_physical(&self, report: &ImuReport) -> ImuSample {
+        let full_scale = 2f64.powi(REGISTER_BITS - 1);
+        let accel_scale = self.accel_range * GRAVITY / full_scale;
+        let gyro_scale = self.gyro_range.to_radians() / full_scale;
+
+        ImuSample {
+            tag_addr: report.tag_addr,
+            system_ts: report.system_ts,
+            accel: report.accel.map(|raw| signed_count(raw) * accel_scale),
+            gyro: report.gyro.map(|raw| signed_count(raw) * gyro_scale),
+        }
+    }
+
+    /// Convert a raw report to calibrated physical units
+    pub fn convert(&self, report: &ImuReport) -> ImuSample {
+        let sample = self.to_physical(report);
+
+        ImuSample {
+            accel: self.accel.apply(sample.accel),
+            gyro: self.gyro.apply(sample.gyro),
+            ..sample
+        }
+    }
+}
+
+/// The tag moved during the static calibration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NotStationary {
+    pub tag: u16,
+    /// Largest standard deviation of the angular rate, in rad/s
+    pub gyro_std: f64,
+    /// Mean norm of the specific force, in m/s²
+    pub accel_norm: f64,
+}
+
+impl fmt::Display for NotStationary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tag {:#06x} was not stationary (gyro std {:.4} rad/s, accel {:.2} m/s²)",
+            self.tag, self.gyro_std, self.accel_norm
+        )
+    }
+}
+
+impl std::error::Error for NotStationary {}
+
+#[derive(Debug, Default, Clone)]
+struct StaticCapture {
+    count: usize,
+    gyro_sum: Vector3<f64>,
+    gyro_sum_squares: Vector3<f64>,
+    accel_norm_sum: f64,
+}
+
+/// Estimate the gyroscope biases of the tags from a stationary capture
+///
+/// The bias is the mean angular rate, and the capture is rejected if the rate
+/// varies, or if the specific force is not gravity.
+pub struct GyroBiasEstimator {
+    /// Largest standard deviation of the angular rate at rest, in rad/s
+    pub max_gyro_std: f64,
+    /// Largest difference between the specific force and gravity, in m/s²
+    pub max_accel_error: f64,
+    captures: BTreeMap<u16, StaticCapture>,
+}
+
+impl Default for GyroBiasEstimator {
+    fn default() -> Self {
+        GyroBiasEstimator {
+            max_gyro_std: 0.02,
+            max_accel_error: 0.5,
+            captures: BTreeMap::new(),
+        }
+    }
+}
+
+impl GyroBiasEstimator {
+    /// Add a sample in physical units, without the gyroscope calibration
+    pub fn add(&mut self, sample: &ImuSample) {
+        let capture = self.captures.entry(sample.tag_addr).or_default();
+        let gyro = sample.gyro();
+
+        capture.count += 1;
+        capture.gyro_sum += gyro;
+        capture.gyro_sum_squares += gyro.component_mul(&gyro);
+        capture.accel_norm_sum += sample.accel().norm();
+    }
+
+    /// Smallest number of samples of all the tags seen so far
+    pub fn min_samples(&self) -> usize {
+        self.captures
+            .values()
+            .map(|capture| capture.count)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Estimate the gyroscope bias of all the tags seen so far
+    pub fn estimate(&self) -> Result<BTreeMap<u16, [f64; 3]>, NotStationary> {
+        let mut biases = BTreeMap::new();
+
+        for (&tag, capture) in self.captures.iter() {
+            let n = capture.count as f64;
+            let mean = capture.gyro_sum / n;
+            let variance = capture.gyro_sum_squares / n - mean.component_mul(&mean);
+            let gyro_std = variance.max().max(0.0).sqrt();
+            let accel_norm = capture.accel_norm_sum / n;
+
+            if gyro_std > self.max_gyro_std || (accel_norm - GRAVITY).abs() > self.max_accel_error {
+                return Err(NotStationary {
+                    tag,
+                    gyro_std,
+                    accel_norm,
+                });
+            }
+
+            biases.insert(tag, mean.into());
+        }
+
+        Ok(biases)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_imu_conversion() {
+        let report = ImuReport {
+            tag_addr: 1,
+            system_ts: 1_500_000,
+            // Zero and sign extended negative values
+            accel: [0, 0x2000, 0xffff_e000],
+            gyro: [0x4000, 0, 0xffff],
+        };
+
+        let configuration = ImuConfiguration::default();
+        let sample = configuration.convert(&report);
+        assert_eq!(sample.time(), 1.5);
+        assert!((sample.accel() - Vector3::new(0.0, GRAVITY, -GRAVITY)).norm() < 1e-9);
+        assert!((sample.gyro[0] - 1000f64.to_radians()).abs() < 1e-9);
+        assert!(sample.gyro[2] < 0.0);
+
+        let report = ImuReport {
+            accel: [0, 0xe000, 0],
+            ..report
+        };
+        assert_eq!(
+            configuration.convert(&report).accel,
+            configuration.to_physical(&report).accel
+        );
+
+        // Full scale and calibration
+        let configuration = ImuConfiguration {
+            accel_range: 8.0,
+            accel: SensorCalibration {
+                bias: [0.0, -0.5, 0.0],
+                scale: [1.0, 2.0, 1.0],
+                misalignment: [[0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]],
+            },
+            ..Default::default()
+        };
+        let sample = configuration.convert(&report);
+        assert!((sample.accel[0] - (-2.0 * GRAVITY + 0.5) * 2.0).abs() < 1e-9);
+        assert_eq!(sample.accel[1], 0.0);
+    }
+
+    #[test]
+    fn test_gyro_bias_estimation() {
+        let bias = [0.01, -0.02, 0.005];
+        let mut estimator = GyroBiasEstimator::default();
+
+        for i in 0..1000 {
+            let noise = 0.001 * ((i % 7) as f64 - 3.0);
+            estimator.add(&ImuSample {
+                tag_addr: 1,
+                system_ts: i * 1000,
+                accel: [0.0, 0.0, GRAVITY + noise],
+                gyro: bias.map(|b| b + noise),
+            });
+        }
+        assert_eq!(estimator.min_samples(), 1000);
+
+        let biases = estimator.estimate().unwrap();
+        for (estimated, truth) in biases[&1].iter().zip(bias) {
+            assert!((estimated - truth).abs() < 1e-3);
+        }
+
+        // A rotating tag is rejected
+        for i in 0..1000 {
+            estimator.add(&ImuSample {
+                tag_addr: 2,
+                system_ts: i * 1000,
+                accel: [0.0, 0.0, GRAVITY],
+                gyro: [0.0, 0.0, if i % 2 == 0 { 0.5 } else { 0.0 }],
+            });
+        }
+        assert_eq!(estimator.estimate().unwrap_err().tag, 2);
+    }
+}
@@ -4,7 +4,30 @@ pub mod command_line;
 pub mod proto;
 // Async stream decoder for the custom wire format.
 pub mod stream_decoder;
+// Encoder for the custom wire format.
+pub mod stream_encoder;
+// Serial port selection by USB identity
+pub mod port_selector;
+
+// Serial ports reopened on failure
+pub mod serial_source;
 // Optimization for the location of the device
 pub mod optimization;
+// Range bias and scale calibration
+pub mod calibration;
+// Device clock offset and skew estimation
+pub mod clock;
+// Synchronization of the range reports of the serial ports
+pub mod synchronizer;
+// Anchor self-survey from inter-anchor ranges
+pub mod survey;
+// Per-tag Kalman filter tracking on the ranges
+pub mod tracking;
+// IMU sample conversion and calibration
+pub mod imu;
+// UWB and IMU fusion (error-state EKF)
+pub mod fusion;
+// IMU timing diagnostics
+pub mod diagnostics;
 
 pub mod configuration;
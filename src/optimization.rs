@@ -1,87 +1,659 @@
-use nalgebra::{DMatrix, Vector3};
-use tracing::{debug, info};
+use nalgebra::{DMatrix, DVector, Matrix2, Matrix3, Vector3};
+use tracing::debug;
 
-use crate::configuration;
+use crate::configuration::Anchor;
 
-fn least_squares_solution(points: &[Vector3<f64>], distances: &[f64]) -> Option<Vector3<f64>> {
+/// Minimum number of ranges for an unambiguous 3D solution
+pub const MIN_ANCHORS: usize = 4;
+
+/// Loss applied to the range residuals
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Loss {
+    /// Ordinary least squares
+    Squared,
+    /// Quadratic up to the given residual, in meters, then linear
+    Huber(f64),
+    /// Logarithmic above the given residual scale, in meters
+    Cauchy(f64),
+}
+
+impl Loss {
+    /// Cost of a residual, equal to its square for small residuals
+    pub fn cost(&self, residual: f64) -> f64 {
+        match *self {
+            Loss::Squared => residual.powi(2),
+            Loss::Huber(k) if residual.abs() <= k => residual.powi(2),
+            Loss::Huber(k) => 2.0 * k * residual.abs() - k.powi(2),
+            Loss::Cauchy(c) => c.powi(2) * (residual / c).powi(2).ln_1p(),
+        }
+    }
+
+    /// Weight of a residual in the iteratively reweighted least squares
+    pub fn weight(&self, residual: f64) -> f64 {
+        match *self {
+            Loss::Squared => 1.0,
+            Loss::Huber(k) if residual.abs() <= k => 1.0,
+            Loss::Huber(k) => k / residual.abs(),
+            Loss::Cauchy(c) => 1.0 / (1.0 + (residual / c).powi(2)),
+        }
+    }
+}
+
+/// Constraint on the height (z) of the solution
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Height {
+    /// Solve for x, y and z
+    Free,
+    /// Solve for x and y only, at the given height
+    Fixed(f64),
+    /// Soft prior on the height, with its standard deviation, in meters
+    Prior { height: f64, sigma: f64 },
+}
+
+impl Height {
+    /// Minimum number of ranges to solve with this constraint
+    pub fn min_anchors(&self) -> usize {
+        match self {
+            Height::Free => MIN_ANCHORS,
+            _ => MIN_ANCHORS - 1,
+        }
+    }
+
+    /// Weight of the height prior relative to a range residual
+    fn prior_weight(sigma: f64, range_sigma: f64) -> f64 {
+        (range_sigma / sigma).powi(2)
+    }
+
+    fn cost(&self, position: &Vector3<f64>, range_sigma: f64) -> f64 {
+        match *self {
+            Height::Prior { height, sigma } => {
+                Self::prior_weight(sigma, range_sigma) * (position.z - height).powi(2)
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Apply the constraint to the normal equations at `position`
+    fn constrain(
+        &self,
+        hessian: &mut Matrix3<f64>,
+        gradient: &mut Vector3<f64>,
+        position: &Vector3<f64>,
+        range_sigma: f64,
+    ) {
+        match *self {
+            Height::Free => {}
+            Height::Fixed(_) => {
+                hessian.set_row(2, &Vector3::zeros().transpose());
+                hessian.set_column(2, &Vector3::zeros());
+                hessian[(2, 2)] = 1.0;
+                gradient[2] = 0.0;
+            }
+            Height::Prior { height, sigma } => {
+                let weight = Self::prior_weight(sigma, range_sigma);
+                hessian[(2, 2)] += weight;
+                gradient[2] += weight * (position.z - height);
+            }
+        }
+    }
+}
+
+/// Options of the iterative multilateration solver
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SolverOptions {
+    /// Maximum number of Levenberg-Marquardt iterations
+    pub max_iterations: usize,
+    /// Converged when the step is smaller than this, in meters
+    pub step_tolerance: f64,
+    /// Converged when the relative decrease of the cost is smaller than this
+    pub cost_tolerance: f64,
+    /// Initial damping factor
+    pub initial_damping: f64,
+    /// Standard deviation of the range noise, in meters, for the covariance
+    pub range_sigma: f64,
+    /// Loss applied to the range residuals
+    pub loss: Loss,
+    /// Reject the anchors with a larger residual (RANSAC), in meters
+    pub outlier_threshold: Option<f64>,
+    /// Constraint on the height of the solution
+    pub height: Height,
+}
+
+impl Default for SolverOptions {
+    fn default() -> Self {
+        SolverOptions {
+            max_iterations: 50,
+            step_tolerance: 1e-6,
+            cost_tolerance: 1e-12,
+            initial_damping: 1e-3,
+            range_sigma: 0.1,
+            loss: Loss::Squared,
+            outlier_threshold: None,
+            height: Height::Free,
+        }
+    }
+}
+
+/// Result of the iterative multilateration solver
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Solution {
+    pub position: Vector3<f64>,
+    /// Whether one of the convergence criteria was met
+    pub converged: bool,
+    /// Number of iterations performed
+    pub iterations: usize,
+    /// Final cost, the sum of the squared range residuals with `Loss::Squared`
+    pub cost: f64,
+}
+
+/// Solution with its uncertainty
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fix {
+    pub solution: Solution,
+    /// Position covariance, in m², assuming independent range errors
+    pub covariance: Matrix3<f64>,
+    /// Geometric dilution of precision (no clock term, i.e. the PDOP)
+    pub gdop: f64,
+    /// Horizontal dilution of precision
+    pub hdop: f64,
+    /// Vertical dilution of precision
+    pub vdop: f64,
+    /// Number of anchors with a valid range
+    pub anchors_used: usize,
+    /// RMS of the range residuals, in meters
+    pub rms_residual: f64,
+    /// Ids of the anchors rejected as outliers
+    pub rejected: Vec<usize>,
+}
+
+fn range_cost(points: &[Vector3<f64>], distances: &[f64], guess: &Vector3<f64>) -> f64 {
+    points
+        .iter()
+        .zip(distances)
+        .map(|(point, distance)| ((guess - point).norm() - distance).powi(2))
+        .sum()
+}
+
+fn robust_cost(
+    points: &[Vector3<f64>],
+    distances: &[f64],
+    guess: &Vector3<f64>,
+    loss: Loss,
+) -> f64 {
+    points
+        .iter()
+        .zip(distances)
+        .map(|(point, distance)| loss.cost((guess - point).norm() - distance))
+        .sum()
+}
+
+/// Weights of the residuals at a solution, as in the last reweighting of the solver
+fn robust_weights(
+    points: &[Vector3<f64>],
+    distances: &[f64],
+    guess: &Vector3<f64>,
+    loss: Loss,
+) -> Vec<f64> {
+    points
+        .iter()
+        .zip(distances)
+        .map(|(point, distance)| loss.weight((guess - point).norm() - distance))
+        .collect()
+}
+
+/// Damped (Levenberg-Marquardt) multilateration from the given initial guess
+///
+/// Returns `None` if the inputs are inconsistent, otherwise the best solution
+/// found, which may not have converged. A robust `options.loss` is minimized by
+/// reweighting the residuals at each iteration. With a `Height::Fixed` constraint
+/// the height of `initial` is replaced and only x and y are solved.
+pub fn levenberg_marquardt(
+    points: &[Vector3<f64>],
+    distances: &[f64],
+    initial: Vector3<f64>,
+    options: &SolverOptions,
+) -> Option<Solution> {
     if points.len() != distances.len() || points.is_empty() {
         return None;
     }
 
-    // Initialize the guess for the unknown point (e.g., to the origin)
-    let mut guess = Vector3::new(0.0, 0.0, 0.0);
+    let mut guess = initial;
+    if let Height::Fixed(height) = options.height {
+        guess.z = height;
+    }
+
+    let loss = options.loss;
+    let total_cost = |guess: &Vector3<f64>| {
+        robust_cost(points, distances, guess, loss)
+            + options.height.cost(guess, options.range_sigma)
+    };
+    let mut cost = total_cost(&guess);
+    let mut damping = options.initial_damping;
+    let mut converged = false;
+    let mut iterations = 0;
 
-    // Define the maximum number of iterations and a tolerance for convergence
-    let max_iterations = 5;
-    let tolerance = 1e-3;
+    while iterations < options.max_iterations {
+        iterations += 1;
 
-    for _ in 0..max_iterations {
         // Compute the Jacobian matrix and the residuals
         let mut jacobian = DMatrix::zeros(points.len(), 3);
-        let mut residuals = DMatrix::zeros(points.len(), 1);
+        let mut residuals = DVector::zeros(points.len());
 
         for (i, (&point, &distance)) in points.iter().zip(distances).enumerate() {
-            let diff = point - guess;
+            let diff = guess - point;
             let dist = diff.norm();
+            let weight = loss.weight(dist - distance).sqrt();
 
-            if dist < 1e-6 {
-                // Zeroing out the Jacobian matrix
-                jacobian[(i, 0)] = diff.x / 1e-6;
-                jacobian[(i, 1)] = diff.y / 1e-6;
-                jacobian[(i, 2)] = diff.z / 1e-6;
-            } else {
-                // Fill in the Jacobian matrix
-                jacobian[(i, 0)] = diff.x / dist;
-                jacobian[(i, 1)] = diff.y / dist;
-                jacobian[(i, 2)] = diff.z / dist;
+            // The range is not differentiable at the anchor, leave the row at zero
+            if dist > 1e-9 {
+                jacobian.set_row(i, &(diff * weight / dist).transpose());
             }
 
-            // Compute the residual
-            residuals[(i, 0)] = dist - distance;
+            residuals[i] = (dist - distance) * weight;
         }
 
-        // Update the guess using the Gauss-Newton method
-        let delta = jacobian.clone().pseudo_inverse(1e-9).unwrap() * residuals.clone();
-        guess += delta.fixed_rows::<3>(0);
+        let mut hessian: Matrix3<f64> = (jacobian.transpose() * &jacobian)
+            .fixed_view::<3, 3>(0, 0)
+            .into();
+        let mut gradient: Vector3<f64> = (jacobian.transpose() * &residuals)
+            .fixed_rows::<3>(0)
+            .into();
+        options
+            .height
+            .constrain(&mut hessian, &mut gradient, &guess, options.range_sigma);
+
+        // Increase the damping until the step decreases the cost
+        let mut step = None;
+        while damping < 1e10 {
+            let damped =
+                hessian + Matrix3::from_diagonal(&hessian.diagonal().add_scalar(1e-9)) * damping;
+            if let Some(delta) = damped.cholesky().map(|c| c.solve(&-gradient)) {
+                let candidate_cost = total_cost(&(guess + delta));
+                if candidate_cost < cost {
+                    step = Some((delta, candidate_cost));
+                    damping = (damping / 10.0).max(1e-12);
+                    break;
+                }
+            }
+            damping *= 10.0;
+        }
+
+        let Some((delta, new_cost)) = step else {
+            // No descent direction left: we are at a (local) minimum
+            converged = gradient.norm() < 1e-9 || cost < options.cost_tolerance;
+            break;
+        };
+
+        guess += delta;
+        let decrease = cost - new_cost;
+        cost = new_cost;
 
         // Check for convergence
-        if residuals.norm_squared() < tolerance {
-            return Some(guess);
+        if delta.norm() < options.step_tolerance * (guess.norm() + options.step_tolerance)
+            || decrease <= options.cost_tolerance * cost.max(options.cost_tolerance)
+        {
+            converged = true;
+            break;
         }
     }
 
     // Calculate residuals for each anchor
-    let mut residuals = Vec::new();
-    for (i, (&point, &distance)) in points.iter().zip(distances).enumerate() {
-        let diff = point - guess;
+    let residuals: Vec<_> = points
+        .iter()
+        .zip(distances)
+        .map(|(point, distance)| (guess - point).norm() - distance)
+        .enumerate()
+        .collect();
+    debug!("Residuals: {:+0.3?}", residuals);
+
+    Some(Solution {
+        position: guess,
+        converged,
+        iterations,
+        cost,
+    })
+}
+
+/// Next subset of `k` indices out of `n` in lexicographic order
+fn next_combination(subset: &mut [usize], n: usize) -> bool {
+    let k = subset.len();
+    for i in (0..k).rev() {
+        if subset[i] < n - k + i {
+            subset[i] += 1;
+            for j in i + 1..k {
+                subset[j] = subset[j - 1] + 1;
+            }
+            return true;
+        }
+    }
+    false
+}
+
+/// Robust multilateration, rejecting the ranges inconsistent with the others (RANSAC)
+///
+/// Every minimal subset of ranges is solved, the solution agreeing with the
+/// most ranges within `threshold` is refined on those inliers. The subsets are
+/// enumerated exhaustively, which is cheap for the anchor counts of a site.
+/// Returns the refined solution and the indices of the rejected ranges.
+pub fn ransac(
+    points: &[Vector3<f64>],
+    distances: &[f64],
+    initial: Vector3<f64>,
+    threshold: f64,
+    options: &SolverOptions,
+) -> Option<(Solution, Vec<usize>)> {
+    if points.len() != distances.len() {
+        return None;
+    }
+    // Too few ranges to tell an outlier
+    let min_anchors = options.height.min_anchors();
+    if points.len() <= min_anchors {
+        return levenberg_marquardt(points, distances, initial, options)
+            .map(|solution| (solution, Vec::new()));
+    }
+
+    let mut best: Option<(Vec<usize>, f64, Vector3<f64>)> = None;
+    let mut subset: Vec<usize> = (0..min_anchors).collect();
+    loop {
+        let subset_points: Vec<_> = subset.iter().map(|&i| points[i]).collect();
+        let subset_distances: Vec<_> = subset.iter().map(|&i| distances[i]).collect();
+
+        if let Some(solution) =
+            levenberg_marquardt(&subset_points, &subset_distances, initial, options)
+        {
+            let inliers: Vec<usize> = (0..points.len())
+                .filter(|&i| {
+                    ((solution.position - points[i]).norm() - distances[i]).abs() <= threshold
+                })
+                .collect();
+            let inlier_points: Vec<_> = inliers.iter().map(|&i| points[i]).collect();
+            let inlier_distances: Vec<_> = inliers.iter().map(|&i| distances[i]).collect();
+            let cost = range_cost(&inlier_points, &inlier_distances, &solution.position);
+
+            let better = match &best {
+                None => true,
+                Some((best_inliers, best_cost, _)) => {
+                    inliers.len() > best_inliers.len()
+                        || (inliers.len() == best_inliers.len() && cost < *best_cost)
+                }
+            };
+            if better {
+                best = Some((inliers, cost, solution.position));
+            }
+        }
+
+        if !next_combination(&mut subset, points.len()) {
+            break;
+        }
+    }
+
+    let (inliers, _, position) = best?;
+    let inlier_points: Vec<_> = inliers.iter().map(|&i| points[i]).collect();
+    let inlier_distances: Vec<_> = inliers.iter().map(|&i| distances[i]).collect();
+    let solution = levenberg_marquardt(&inlier_points, &inlier_distances, position, options)?;
+
+    let rejected = (0..points.len()).filter(|i| !inliers.contains(i)).collect();
+    Some((solution, rejected))
+}
+
+/// Covariance and dilution of precision of a solution
+///
+/// The covariance is `σ² (JᵀWJ)⁻¹`, with `J` the Jacobian of the ranges (and of the
+/// height prior) at the solution and `W` the `weights` of the robust loss (all ones
+/// for least squares). It is infinite when the geometry does not constrain the
+/// position. A fixed height has no variance.
+pub fn fix_quality(
+    points: &[Vector3<f64>],
+    distances: &[f64],
+    weights: &[f64],
+    solution: Solution,
+    options: &SolverOptions,
+) -> Fix {
+    let mut hessian = Matrix3::zeros();
+    for (point, weight) in points.iter().zip(weights) {
+        let diff = solution.position - point;
         let dist = diff.norm();
-        residuals.push((i, dist - distance));
+        if dist > 1e-9 {
+            let direction = diff / dist;
+            hessian += direction * direction.transpose() * *weight;
+        }
     }
 
-    // Print the residuals
-    info!("Residuals: {:+0.3?}", residuals);
+    let dop = match options.height {
+        Height::Fixed(_) => {
+            let horizontal: Matrix2<f64> = hessian.fixed_view::<2, 2>(0, 0).into();
+            horizontal.try_inverse().map(|q| {
+                let mut dop = Matrix3::zeros();
+                dop.fixed_view_mut::<2, 2>(0, 0).copy_from(&q);
+                dop
+            })
+        }
+        height => {
+            let mut gradient = Vector3::zeros();
+            height.constrain(
+                &mut hessian,
+                &mut gradient,
+                &solution.position,
+                options.range_sigma,
+            );
+            hessian.try_inverse()
+        }
+    };
+    let dop = dop
+        .filter(|q| q.diagonal().iter().all(|v| v.is_finite() && *v >= 0.0))
+        .unwrap_or_else(|| Matrix3::from_element(f64::INFINITY));
+
+    let rms_residual = if points.is_empty() {
+        0.0
+    } else {
+        (range_cost(points, distances, &solution.position) / points.len() as f64).sqrt()
+    };
 
-    Some(guess) // Return the best guess
+    Fix {
+        solution,
+        covariance: dop * options.range_sigma.powi(2),
+        gdop: dop.trace().sqrt(),
+        hdop: (dop[(0, 0)] + dop[(1, 1)]).sqrt(),
+        vdop: dop[(2, 2)].sqrt(),
+        anchors_used: points.len(),
+        rms_residual,
+        rejected: Vec::new(),
+    }
+}
+
+/// Position covariance to start a filter from
+///
+/// The covariance of a fix is infinite when the geometry does not constrain the
+/// position, so a non-finite covariance falls back to `sigma²` on each axis.
+pub fn initial_covariance(covariance: &Matrix3<f64>, sigma: f64) -> Matrix3<f64> {
+    if covariance.iter().all(|v| v.is_finite()) {
+        *covariance
+    } else {
+        Matrix3::identity() * sigma.powi(2)
+    }
+}
+
+/// Closed-form linearized multilateration
+///
+/// Subtracting the squared-range equation of a reference point from the others
+/// gives a linear system in the position, solved in the least squares sense. The
+/// point with the shortest range is the reference, as it usually has the smallest
+/// error. If the points are coplanar, the position is only determined in their
+/// plane and the least norm solution is returned.
+///
+/// Returns `None` with fewer than `MIN_ANCHORS` points or inconsistent inputs.
+pub fn linear_multilateration(points: &[Vector3<f64>], distances: &[f64]) -> Option<Vector3<f64>> {
+    if points.len() < MIN_ANCHORS || points.len() != distances.len() {
+        return None;
+    }
+
+    let reference = (0..distances.len()).min_by(|&a, &b| distances[a].total_cmp(&distances[b]))?;
+    let (p0, d0) = (points[reference], distances[reference]);
+
+    let mut a = DMatrix::zeros(points.len() - 1, 3);
+    let mut b = DVector::zeros(points.len() - 1);
+    let others = (0..points.len()).filter(|&i| i != reference);
+    for (row, i) in others.enumerate() {
+        let (p, d) = (points[i], distances[i]);
+        a.set_row(row, &(2.0 * (p - p0)).transpose());
+        b[row] = d0.powi(2) - d.powi(2) + p.norm_squared() - p0.norm_squared();
+    }
+
+    let svd = a.svd(true, true);
+    let eps = svd.singular_values.max() * 1e-9;
+    let x = svd.solve(&b, eps).ok()?;
+    let x = Vector3::new(x[0], x[1], x[2]);
+
+    x.iter().all(|v| v.is_finite()).then_some(x)
+}
+
+/// Closed-form linearized multilateration at a known height
+///
+/// Same as `linear_multilateration`, solving only for x and y, which needs one
+/// point less. Returns `None` with fewer than 3 points or inconsistent inputs.
+pub fn linear_multilateration_at_height(
+    points: &[Vector3<f64>],
+    distances: &[f64],
+    height: f64,
+) -> Option<Vector3<f64>> {
+    if points.len() < MIN_ANCHORS - 1 || points.len() != distances.len() {
+        return None;
+    }
+
+    let reference = (0..distances.len()).min_by(|&a, &b| distances[a].total_cmp(&distances[b]))?;
+    let (p0, d0) = (points[reference], distances[reference]);
+
+    let mut a = DMatrix::zeros(points.len() - 1, 2);
+    let mut b = DVector::zeros(points.len() - 1);
+    let others = (0..points.len()).filter(|&i| i != reference);
+    for (row, i) in others.enumerate() {
+        let (p, d) = (points[i], distances[i]);
+        a[(row, 0)] = 2.0 * (p.x - p0.x);
+        a[(row, 1)] = 2.0 * (p.y - p0.y);
+        b[row] = d0.powi(2) - d.powi(2) + p.norm_squared()
+            - p0.norm_squared()
+            - 2.0 * (p.z - p0.z) * height;
+    }
+
+    let svd = a.svd(true, true);
+    let eps = svd.singular_values.max() * 1e-9;
+    let x = svd.solve(&b, eps).ok()?;
+    let x = Vector3::new(x[0], x[1], height);
+
+    x.iter().all(|v| v.is_finite()).then_some(x)
 }
 
 /// Try localize a point with the given distances to the anchors
 ///
-/// The function returns the estimated point and the error
-pub fn localize_point(distances: &[f64]) -> Option<Vector3<f64>> {
+/// `distances` is indexed by anchor id, as in `RangeReport.ranges`. The solver
+/// starts from `initial`, typically the previous position of the tag, or from
+/// `linear_multilateration` if it is `None`. The height of the point is
+/// constrained by `options.height`.
+/// The function returns the estimated point, the solver status and the uncertainty,
+/// or `None` with fewer valid ranges (or inliers) than `options.height.min_anchors()`,
+/// which do not determine the position.
+pub fn localize_point(
+    anchors: &[Anchor],
+    distances: &[f64],
+    initial: Option<Vector3<f64>>,
+    options: &SolverOptions,
+) -> Option<Fix> {
     let mut points = Vec::new();
     let mut distances_valid = Vec::<f64>::new();
+    let mut ids = Vec::new();
 
-    for (i, &distance) in distances.iter().enumerate() {
+    for anchor in anchors {
+        let Some(&distance) = distances.get(anchor.id) else {
+            continue;
+        };
         if !distance.is_normal() {
             continue;
         }
 
-        let point = configuration::COORDINATES[i];
-        points.push(Vector3::new(point.0, point.1, point.2));
+        points.push(anchor.position());
         distances_valid.push(distance);
+        ids.push(anchor.id);
+    }
+
+    let min_anchors = options.height.min_anchors();
+    if points.len() < min_anchors {
+        return None;
+    }
+
+    let initial = initial
+        .or_else(|| match options.height {
+            Height::Free => linear_multilateration(&points, &distances_valid),
+            Height::Fixed(height) | Height::Prior { height, .. } => {
+                linear_multilateration_at_height(&points, &distances_valid, height)
+            }
+        })
+        .unwrap_or_else(Vector3::zeros);
+
+    let Some(threshold) = options.outlier_threshold else {
+        let solution = levenberg_marquardt(&points, &distances_valid, initial, options)?;
+        let weights = robust_weights(&points, &distances_valid, &solution.position, options.loss);
+        return Some(fix_quality(
+            &points,
+            &distances_valid,
+            &weights,
+            solution,
+            options,
+        ));
+    };
+
+    let (solution, rejected) = ransac(&points, &distances_valid, initial, threshold, options)?;
+
+    let inliers: Vec<usize> = (0..points.len())
+        .filter(|i| !rejected.contains(i))
+        .collect();
+    if inliers.len() < min_anchors {
+        return None;
     }
+    let inlier_points: Vec<_> = inliers.iter().map(|&i| points[i]).collect();
+    let inlier_distances: Vec<_> = inliers.iter().map(|&i| distances_valid[i]).collect();
 
-    return least_squares_solution(&points, &distances_valid);
+    let weights = robust_weights(
+        &inlier_points,
+        &inlier_distances,
+        &solution.position,
+        options.loss,
+    );
+    let mut fix = fix_quality(
+        &inlier_points,
+        &inlier_distances,
+        &weights,
+        solution,
+        options,
+    );
+    fix.rejected = rejected.iter().map(|&i| ids[i]).collect();
+    Some(fix)
+}
+
+/// Site and ranges shared by the tests of the localization based filters
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use nalgebra::Vector3;
+
+    use crate::configuration::SiteConfiguration;
+
+    /// Five anchors around a 6 m square, at different heights
+    pub fn site() -> SiteConfiguration {
+        SiteConfiguration::from_coordinates(&[
+            (0.0, 0.0, 0.0),
+            (6.0, 0.0, 2.0),
+            (0.0, 6.0, 2.0),
+            (6.0, 6.0, 0.0),
+            (3.0, 3.0, 3.0),
+        ])
+    }
+
+    /// Exact ranges from `position` to the anchors, indexed by anchor id
+    pub fn ranges(site: &SiteConfiguration, position: Vector3<f64>) -> Vec<f64> {
+        site.anchors
+            .iter()
+            .map(|anchor| (anchor.position() - position).norm())
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -105,8 +677,336 @@ mod tests {
             0.0,
         ];
 
-        let solution = least_squares_solution(&points, &distances).unwrap();
+        let solution = levenberg_marquardt(
+            &points,
+            &distances,
+            Vector3::zeros(),
+            &SolverOptions::default(),
+        )
+        .unwrap();
+
+        assert!(solution.converged);
+        assert!((solution.position - Vector3::new(1.0, 1.0, 1.0)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn test_linear_multilateration() {
+        let points = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector3::new(1.0, 1.0, 1.0),
+        ];
+        let distances = [
+            3.0f64.sqrt(),
+            2.0f64.sqrt(),
+            2.0f64.sqrt(),
+            2.0f64.sqrt(),
+            0.0,
+        ];
+
+        let position = linear_multilateration(&points, &distances).unwrap();
+        assert!((position - Vector3::new(1.0, 1.0, 1.0)).norm() < 1e-9);
+
+        // Any four non-coplanar points are enough
+        let position = linear_multilateration(&points[..4], &distances[..4]).unwrap();
+        assert!((position - Vector3::new(1.0, 1.0, 1.0)).norm() < 1e-9);
+
+        // Noisy ranges give an approximate solution, refined by the iterative solver
+        let noisy = [
+            distances[0] + 0.01,
+            distances[1] - 0.02,
+            distances[2] + 0.015,
+            distances[3] - 0.01,
+            0.02,
+        ];
+        let initial = linear_multilateration(&points, &noisy).unwrap();
+        assert!((initial - Vector3::new(1.0, 1.0, 1.0)).norm() < 0.1);
+        let solution =
+            levenberg_marquardt(&points, &noisy, initial, &SolverOptions::default()).unwrap();
+        assert!(solution.converged);
+        assert!(solution.cost <= range_cost(&points, &noisy, &initial));
+
+        assert!(linear_multilateration(&points[..3], &distances[..3]).is_none());
+        assert!(linear_multilateration(&points, &distances[..4]).is_none());
+    }
+
+    #[test]
+    fn test_localize_point() {
+        use crate::configuration::SiteConfiguration;
+
+        let site = SiteConfiguration::from_coordinates(&[
+            (0.0, 0.0, 0.0),
+            (4.0, 0.0, 0.0),
+            (0.0, 4.0, 0.0),
+            (0.0, 0.0, 4.0),
+            (4.0, 4.0, 4.0),
+        ]);
+        let truth = Vector3::new(1.0, 2.0, 0.5);
+
+        let mut distances: Vec<f64> = site
+            .anchors
+            .iter()
+            .map(|anchor| (anchor.position() - truth).norm())
+            .collect();
+        // Missing ranges are skipped
+        distances[4] = f64::NAN;
+
+        let options = SolverOptions::default();
+        let fix = localize_point(&site.anchors, &distances, None, &options).unwrap();
+        let solution = fix.solution;
+
+        assert_eq!(fix.anchors_used, 4);
+        assert!(fix.rms_residual < 1e-6);
+        assert!(solution.converged);
+        assert!(solution.cost < 1e-12);
+        assert!((solution.position - truth).norm() < 1e-6);
+
+        // Warm start from a nearby position
+        let initial = truth + Vector3::new(0.1, -0.1, 0.05);
+        let warm = localize_point(&site.anchors, &distances, Some(initial), &options).unwrap();
+        assert!(warm.solution.converged);
+        assert!((warm.solution.position - truth).norm() < 1e-6);
+    }
+
+    #[test]
+    fn test_fix_quality() {
+        // Anchors along the axes, on both sides of the solution
+        let points = [
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(-1.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::new(0.0, -1.0, 0.0),
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector3::new(0.0, 0.0, -1.0),
+        ];
+        let distances = [1.1, 0.9, 1.0, 1.0, 1.0, 1.0];
+        let solution = Solution {
+            position: Vector3::zeros(),
+            converged: true,
+            iterations: 1,
+            cost: 0.0,
+        };
+
+        // JᵀJ = 2 I
+        let options = SolverOptions::default();
+        let fix = fix_quality(&points, &distances, &[1.0; 6], solution, &options);
+        assert_eq!(fix.anchors_used, 6);
+        assert!((fix.covariance - Matrix3::identity() * 0.005).norm() < 1e-12);
+        assert!((fix.gdop - 1.5f64.sqrt()).abs() < 1e-12);
+        assert!((fix.hdop - 1.0).abs() < 1e-12);
+        assert!((fix.vdop - 0.5f64.sqrt()).abs() < 1e-12);
+        assert!((fix.rms_residual - (0.02f64 / 6.0).sqrt()).abs() < 1e-12);
+
+        // Anchors in a plane through the solution do not constrain the height
+        let fix = fix_quality(&points[..4], &distances[..4], &[1.0; 4], solution, &options);
+        assert!(fix.vdop.is_infinite());
+
+        // Unless the height is fixed, or has a prior
+        let fixed = SolverOptions {
+            height: Height::Fixed(0.0),
+            ..options
+        };
+        let fix = fix_quality(&points[..4], &distances[..4], &[1.0; 4], solution, &fixed);
+        assert_eq!(fix.vdop, 0.0);
+        assert!((fix.hdop - 1.0).abs() < 1e-12);
+
+        let prior = SolverOptions {
+            height: Height::Prior {
+                height: 0.0,
+                sigma: 0.2,
+            },
+            ..options
+        };
+        let fix = fix_quality(&points[..4], &distances[..4], &[1.0; 4], solution, &prior);
+        assert!((fix.covariance[(2, 2)] - 0.04).abs() < 1e-12);
+
+        // A down-weighted anchor contributes less information: JᵀWJ = diag(2, 2, 1)
+        let weights = [1.0, 1.0, 1.0, 1.0, 1.0, 0.0];
+        let fix = fix_quality(&points, &distances, &weights, solution, &options);
+        assert!((fix.vdop - 1.0).abs() < 1e-12);
+        assert!((fix.covariance[(2, 2)] - 0.01).abs() < 1e-12);
+        assert!((fix.hdop - 1.0).abs() < 1e-12);
+    }
+
+    fn nlos_geometry() -> (Vec<Vector3<f64>>, Vec<f64>, Vector3<f64>) {
+        let points = vec![
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(4.0, 0.0, 0.0),
+            Vector3::new(0.0, 4.0, 0.0),
+            Vector3::new(0.0, 0.0, 4.0),
+            Vector3::new(4.0, 4.0, 4.0),
+            Vector3::new(4.0, 4.0, 0.0),
+            Vector3::new(0.0, 4.0, 4.0),
+        ];
+        let truth = Vector3::new(1.0, 2.0, 0.5);
+        let mut distances: Vec<f64> = points.iter().map(|p| (p - truth).norm()).collect();
+        // NLOS range to the anchor 5
+        distances[5] += 1.5;
+
+        (points, distances, truth)
+    }
+
+    #[test]
+    fn test_robust_loss() {
+        let (points, distances, truth) = nlos_geometry();
+
+        let solve = |loss| {
+            let options = SolverOptions {
+                loss,
+                max_iterations: 200,
+                ..Default::default()
+            };
+            let solution =
+                levenberg_marquardt(&points, &distances, Vector3::zeros(), &options).unwrap();
+            (solution.position - truth).norm()
+        };
+
+        let squared = solve(Loss::Squared);
+        assert!(solve(Loss::Huber(0.1)) < squared);
+        assert!(solve(Loss::Cauchy(0.1)) < squared / 2.0);
+
+        assert_eq!(Loss::Huber(0.5).cost(0.25), 0.0625);
+        assert_eq!(Loss::Huber(0.5).cost(-2.0), 1.75);
+        assert_eq!(Loss::Huber(0.5).weight(2.0), 0.25);
+    }
+
+    #[test]
+    fn test_ransac() {
+        let (points, distances, truth) = nlos_geometry();
+        let options = SolverOptions::default();
+
+        let (solution, rejected) =
+            ransac(&points, &distances, Vector3::zeros(), 0.2, &options).unwrap();
+        assert_eq!(rejected, vec![5]);
+        assert!(solution.converged);
+        assert!((solution.position - truth).norm() < 1e-6);
+
+        // The rejected anchors are reported by id
+        let site = crate::configuration::SiteConfiguration::from_coordinates(
+            &points.iter().map(|p| (p.x, p.y, p.z)).collect::<Vec<_>>(),
+        );
+        let options = SolverOptions {
+            outlier_threshold: Some(0.2),
+            ..Default::default()
+        };
+        let fix = localize_point(&site.anchors[1..], &distances, None, &options).unwrap();
+        assert_eq!(fix.rejected, vec![5]);
+        assert_eq!(fix.anchors_used, 5);
+        assert!(fix.rms_residual < 1e-6);
+    }
+
+    #[test]
+    fn test_height_constraint() {
+        // Anchors at nearly the same height, tag on a ground robot
+        let site = crate::configuration::SiteConfiguration::from_coordinates(&[
+            (0.0, 0.0, 1.3),
+            (6.0, 0.0, 1.35),
+            (0.0, 6.0, 1.25),
+            (6.0, 6.0, 1.3),
+            (3.0, -2.0, 1.32),
+        ]);
+        let truth = Vector3::new(2.0, 3.5, 0.25);
+        let mut distances: Vec<f64> = site
+            .anchors
+            .iter()
+            .map(|anchor| (anchor.position() - truth).norm())
+            .collect();
+        // A small range error
+        distances[1] += 0.05;
+
+        let fixed = SolverOptions {
+            height: Height::Fixed(0.25),
+            ..Default::default()
+        };
+        let fix = localize_point(&site.anchors, &distances, None, &fixed).unwrap();
+        assert!(fix.solution.converged);
+        assert_eq!(fix.solution.position.z, 0.25);
+        assert!((fix.solution.position - truth).norm() < 0.1);
+
+        // Three ranges are enough at a known height
+        let fix = localize_point(&site.anchors[..3], &distances, None, &fixed).unwrap();
+        assert!(fix.solution.converged);
+        assert!((fix.solution.position - truth).norm() < 0.1);
+
+        // A tight prior keeps the height close to its value
+        let prior = SolverOptions {
+            height: Height::Prior {
+                height: 0.25,
+                sigma: 0.01,
+            },
+            ..Default::default()
+        };
+        let fix = localize_point(&site.anchors, &distances, None, &prior).unwrap();
+        assert!(fix.solution.converged);
+        assert!((fix.solution.position.z - 0.25).abs() < 0.02);
+        assert!(fix.covariance[(2, 2)].sqrt() < 0.01);
+
+        let points: Vec<_> = site.anchors.iter().map(|a| a.position()).collect();
+        let initial =
+            linear_multilateration_at_height(&points[..3], &distances[..3], 0.25).unwrap();
+        assert!((initial - truth).norm() < 0.2);
+    }
+
+    #[test]
+    fn test_min_anchors() {
+        let site = crate::configuration::SiteConfiguration::from_coordinates(&[
+            (0.0, 0.0, 1.3),
+            (6.0, 0.0, 1.35),
+            (0.0, 6.0, 1.25),
+            (6.0, 6.0, 2.3),
+        ]);
+        let truth = Vector3::new(2.0, 3.5, 0.25);
+        let distances: Vec<f64> = site
+            .anchors
+            .iter()
+            .map(|anchor| (anchor.position() - truth).norm())
+            .collect();
+
+        // Three ranges do not determine the position in 3D
+        let options = SolverOptions::default();
+        assert!(localize_point(&site.anchors[..3], &distances, None, &options).is_none());
+        assert!(localize_point(&site.anchors, &distances, None, &options).is_some());
+
+        // Nor do two ranges at a known height
+        let fixed = SolverOptions {
+            height: Height::Fixed(0.25),
+            ..Default::default()
+        };
+        assert!(localize_point(&site.anchors[..2], &distances, None, &fixed).is_none());
+
+        // Missing ranges do not count
+        let mut partial = distances.clone();
+        partial[3] = f64::NAN;
+        assert!(localize_point(&site.anchors, &partial, None, &options).is_none());
+    }
+
+    #[test]
+    fn test_solver_limits() {
+        let points = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(4.0, 0.0, 0.0),
+            Vector3::new(0.0, 4.0, 0.0),
+            Vector3::new(0.0, 0.0, 4.0),
+        ];
+        let truth = Vector3::new(3.0, 2.0, 1.0);
+        let distances: Vec<f64> = points.iter().map(|p| (p - truth).norm()).collect();
+
+        // Not enough iterations to converge
+        let options = SolverOptions {
+            max_iterations: 1,
+            ..Default::default()
+        };
+        let solution =
+            levenberg_marquardt(&points, &distances, Vector3::zeros(), &options).unwrap();
+        assert!(!solution.converged);
+        assert_eq!(solution.iterations, 1);
 
-        assert!((solution - Vector3::new(1.0, 1.0, 1.0)).norm() < 1e-6);
+        // Inconsistent inputs
+        assert!(
+            levenberg_marquardt(&points, &distances[..3], Vector3::zeros(), &options).is_none()
+        );
+        assert!(levenberg_marquardt(&[], &[], Vector3::zeros(), &options).is_none());
     }
 }
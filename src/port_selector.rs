@@ -0,0 +1,392 @@
+// Serial port selection
+//
+// The `/dev/ttyACM*` numbering changes with the order the devices are
+// enumerated, so a serial port can also be selected by the USB serial number
+// of its device, by its USB vendor and product ids, or by a udev-style pattern
+// matched against the device path and its `/dev/serial/by-id` and
+// `/dev/serial/by-path` links. The selector is resolved every time the port is
+// opened, so a device keeps its port after being plugged back in.
+
+use std::{fmt, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use tokio_serial::SerialPortType;
+
+/// Directories of the udev links to the serial ports
+const LINK_DIRECTORIES: [&str; 2] = ["/dev/serial/by-id", "/dev/serial/by-path"];
+
+/// Error while parsing or resolving a port selector
+#[derive(Debug)]
+pub enum SelectorError {
+    Invalid(String),
+    Enumeration(tokio_serial::Error),
+    NotFound(PortSelector),
+    /// Several devices match, with their paths
+    Ambiguous(PortSelector, Vec<String>),
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectorError::Invalid(e) => write!(f, "invalid port selector: {}", e),
+            SelectorError::Enumeration(e) => write!(f, "error listing the serial ports: {}", e),
+            SelectorError::NotFound(selector) => write!(f, "no serial port matches {}", selector),
+            SelectorError::Ambiguous(selector, paths) => write!(
+                f,
+                "several serial ports match {}: {}",
+                selector,
+                paths.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SelectorError {}
+
+impl From<tokio_serial::Error> for SelectorError {
+    fn from(e: tokio_serial::Error) -> Self {
+        SelectorError::Enumeration(e)
+    }
+}
+
+/// USB identity of a serial port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbDevice {
+    pub vid: u16,
+    pub pid: u16,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+/// A serial port attached to the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortDescription {
+    /// Device path
+    pub path: String,
+    /// udev links to the device
+    pub links: Vec<String>,
+    pub usb: Option<UsbDevice>,
+}
+
+impl fmt::Display for PortDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let Some(usb) = &self.usb {
+            write!(f, "  {:04x}:{:04x}", usb.vid, usb.pid)?;
+            if let Some(serial_number) = &usb.serial_number {
+                write!(f, "  serial={}", serial_number)?;
+            }
+            let names: Vec<&str> = [&usb.manufacturer, &usb.product]
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .collect();
+            if !names.is_empty() {
+                write!(f, "  {}", names.join(" "))?;
+            }
+        }
+        for link in self.links.iter() {
+            write!(f, "\n    {}", link)?;
+        }
+        Ok(())
+    }
+}
+
+/// List the serial ports attached to the host, sorted by path
+pub fn list_ports() -> Result<Vec<PortDescription>, SelectorError> {
+    // udev links, with the device they point to
+    let mut links = Vec::new();
+    for directory in LINK_DIRECTORIES {
+        let Ok(entries) = std::fs::read_dir(directory) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if let Ok(target) = std::fs::canonicalize(entry.path()) {
+                links.push((entry.path(), target));
+            }
+        }
+    }
+
+    let mut ports: Vec<PortDescription> = tokio_serial::available_ports()?
+        .into_iter()
+        .map(|info| {
+            let usb = match info.port_type {
+                SerialPortType::UsbPort(usb) => Some(UsbDevice {
+                    vid: usb.vid,
+                    pid: usb.pid,
+                    serial_number: usb.serial_number,
+                    manufacturer: usb.manufacturer,
+                    product: usb.product,
+                }),
+                _ => None,
+            };
+            let mut port_links: Vec<String> = links
+                .iter()
+                .filter(|(_, target)| target == Path::new(&info.port_name))
+                .map(|(link, _)| link.to_string_lossy().into_owned())
+                .collect();
+            port_links.sort();
+
+            PortDescription {
+                path: info.port_name,
+                links: port_links,
+                usb,
+            }
+        })
+        .collect();
+    ports.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(ports)
+}
+
+/// Selection of a serial port
+///
+/// Parsed from `serial=<serial number>`, `<vid>:<pid>` in hexadecimal, a
+/// pattern with `*`, `?` or `[...]`, or else a device path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PortSelector {
+    Path(String),
+    SerialNumber(String),
+    VidPid(u16, u16),
+    Pattern(String),
+}
+
+impl PortSelector {
+    /// Whether a serial port is selected
+    pub fn matches(&self, port: &PortDescription) -> bool {
+        match self {
+            PortSelector::Path(path) => {
+                port.path == *path || port.links.iter().any(|link| link == path)
+            }
+            PortSelector::SerialNumber(serial_number) => port
+                .usb
+                .as_ref()
+                .is_some_and(|usb| usb.serial_number.as_ref() == Some(serial_number)),
+            PortSelector::VidPid(vid, pid) => port
+                .usb
+                .as_ref()
+                .is_some_and(|usb| usb.vid == *vid && usb.pid == *pid),
+            PortSelector::Pattern(pattern) => std::iter::once(&port.path)
+                .chain(port.links.iter())
+                .any(|path| glob_match(pattern.as_bytes(), path.as_bytes())),
+        }
+    }
+
+    /// Path of the only selected port among `ports`
+    pub fn select(&self, ports: &[PortDescription]) -> Result<String, SelectorError> {
+        let paths: Vec<String> = ports
+            .iter()
+            .filter(|port| self.matches(port))
+            .map(|port| port.path.clone())
+            .collect();
+
+        match paths.len() {
+            0 => Err(SelectorError::NotFound(self.clone())),
+            1 => Ok(paths.into_iter().next().unwrap()),
+            _ => Err(SelectorError::Ambiguous(self.clone(), paths)),
+        }
+    }
+
+    /// Path of the selected port
+    ///
+    /// A device path is used as is, so that ports which are not listed
+    /// (e.g. pseudo terminals) can be opened.
+    pub fn resolve(&self) -> Result<String, SelectorError> {
+        match self {
+            PortSelector::Path(path) => Ok(path.clone()),
+            _ => self.select(&list_ports()?),
+        }
+    }
+}
+
+impl fmt::Display for PortSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortSelector::Path(path) | PortSelector::Pattern(path) => write!(f, "{}", path),
+            PortSelector::SerialNumber(serial_number) => write!(f, "serial={}", serial_number),
+            PortSelector::VidPid(vid, pid) => write!(f, "{:04x}:{:04x}", vid, pid),
+        }
+    }
+}
+
+impl FromStr for PortSelector {
+    type Err = SelectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(serial_number) = s.strip_prefix("serial=") {
+            if serial_number.is_empty() {
+                return Err(SelectorError::Invalid("empty serial number".into()));
+            }
+            return Ok(PortSelector::SerialNumber(serial_number.into()));
+        }
+
+        if let Some((vid, pid)) = s.split_once(':') {
+            let is_id = |id: &str| id.len() == 4 && id.chars().all(|c| c.is_ascii_hexdigit());
+            if is_id(vid) && is_id(pid) {
+                // Cannot fail, the ids are 4 hexadecimal digits
+                let vid = u16::from_str_radix(vid, 16).unwrap();
+                let pid = u16::from_str_radix(pid, 16).unwrap();
+                return Ok(PortSelector::VidPid(vid, pid));
+            }
+        }
+
+        if s.is_empty() {
+            return Err(SelectorError::Invalid("empty port".into()));
+        }
+        if s.contains(['*', '?', '[']) {
+            return Ok(PortSelector::Pattern(s.into()));
+        }
+        Ok(PortSelector::Path(s.into()))
+    }
+}
+
+impl TryFrom<String> for PortSelector {
+    type Error = SelectorError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PortSelector> for String {
+    fn from(selector: PortSelector) -> Self {
+        selector.to_string()
+    }
+}
+
+/// Match a udev-style pattern: `*` any string, `?` any character, `[...]` a set
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') => (0..=text.len()).any(|skip| glob_match(&pattern[1..], &text[skip..])),
+        Some(b'?') => !text.is_empty() && glob_match(&pattern[1..], &text[1..]),
+        Some(b'[') => {
+            let Some(end) = pattern.iter().skip(2).position(|&c| c == b']') else {
+                // No closing bracket, a literal one
+                return text.first() == Some(&b'[') && glob_match(&pattern[1..], &text[1..]);
+            };
+            let set = &pattern[1..end + 2];
+            let Some(&c) = text.first() else {
+                return false;
+            };
+
+            let (negated, set) = match set.first() {
+                Some(b'!') => (true, &set[1..]),
+                _ => (false, set),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == b'-' {
+                    found |= set[i] <= c && c <= set[i + 2];
+                    i += 3;
+                } else {
+                    found |= set[i] == c;
+                    i += 1;
+                }
+            }
+
+            found != negated && glob_match(&pattern[end + 3..], &text[1..])
+        }
+        Some(&c) => text.first() == Some(&c) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(path: &str, link: &str, vid: u16, pid: u16, serial_number: &str) -> PortDescription {
+        PortDescription {
+            path: path.into(),
+            links: vec![link.into()],
+            usb: Some(UsbDevice {
+                vid,
+                pid,
+                serial_number: Some(serial_number.into()),
+                manufacturer: Some("SEGGER".into()),
+                product: Some("J-Link".into()),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_parse_selector() {
+        let parse = |s: &str| s.parse::<PortSelector>().unwrap();
+        assert_eq!(
+            parse("/dev/ttyACM0"),
+            PortSelector::Path("/dev/ttyACM0".into())
+        );
+        assert_eq!(
+            parse("serial=000683012345"),
+            PortSelector::SerialNumber("000683012345".into())
+        );
+        assert_eq!(parse("1366:1015"), PortSelector::VidPid(0x1366, 0x1015));
+        assert_eq!(
+            parse("/dev/serial/by-id/usb-SEGGER*"),
+            PortSelector::Pattern("/dev/serial/by-id/usb-SEGGER*".into())
+        );
+        assert!("serial=".parse::<PortSelector>().is_err());
+
+        // Read back from the configuration
+        for s in [
+            "/dev/ttyACM0",
+            "serial=000683012345",
+            "1366:1015",
+            "/dev/ttyACM[0-3]",
+        ] {
+            assert_eq!(parse(s).to_string(), s);
+        }
+    }
+
+    #[test]
+    fn test_select_port() {
+        let ports = [
+            port(
+                "/dev/ttyACM0",
+                "/dev/serial/by-id/usb-SEGGER_J-Link_000683012345-if00",
+                0x1366,
+                0x1015,
+                "000683012345",
+            ),
+            port(
+                "/dev/ttyACM1",
+                "/dev/serial/by-id/usb-SEGGER_J-Link_000683054321-if00",
+                0x1366,
+                0x1015,
+                "000683054321",
+            ),
+            port(
+                "/dev/ttyUSB0",
+                "/dev/serial/by-id/usb-FTDI_FT232R_A10K1234-if00-port0",
+                0x0403,
+                0x6001,
+                "A10K1234",
+            ),
+        ];
+        let select = |s: &str| s.parse::<PortSelector>().unwrap().select(&ports);
+
+        assert_eq!(select("serial=000683054321").unwrap(), "/dev/ttyACM1");
+        assert_eq!(select("0403:6001").unwrap(), "/dev/ttyUSB0");
+        assert_eq!(
+            select("/dev/serial/by-id/*012345*").unwrap(),
+            "/dev/ttyACM0"
+        );
+        assert_eq!(select("/dev/ttyUSB[!1-9]").unwrap(), "/dev/ttyUSB0");
+        assert_eq!(
+            select("/dev/serial/by-id/usb-FTDI_FT232R_A10K1234-if00-port0").unwrap(),
+            "/dev/ttyUSB0"
+        );
+
+        assert!(matches!(
+            select("1366:1015"),
+            Err(SelectorError::Ambiguous(_, paths)) if paths.len() == 2
+        ));
+        assert!(matches!(
+            select("serial=unknown"),
+            Err(SelectorError::NotFound(_))
+        ));
+    }
+}
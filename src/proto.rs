@@ -98,3 +98,114 @@ impl From<CirReport> for ConvertedCirReport {
         }
     }
 }
+
+/// Firmware version, sent by the firmware in response to `Command::GetVersion`
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[binrw]
+#[brw(magic = b"VER", little)]
+pub struct VersionReport {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+/// Downlink commands sent from the central to the firmware
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[binrw]
+#[brw(magic = b"CMD", little)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// Set the ranging rate of a tag, in Hz
+    #[brw(magic = 0x01u8)]
+    SetRangingRate { rate_hz: u16 },
+    /// Enable or disable the CIR dumps of an anchor
+    #[brw(magic = 0x02u8)]
+    SetCirDump {
+        #[br(map = |x: u8| x != 0)]
+        #[bw(map = |x: &bool| u8::from(*x))]
+        enable: bool,
+    },
+    /// Request a `VersionReport`
+    #[brw(magic = 0x03u8)]
+    GetVersion,
+    /// Reboot the device
+    #[brw(magic = 0x04u8)]
+    Reboot,
+}
+
+/// A decoded packet from the wire, dispatched on its three-byte magic
+#[derive(Debug, Clone)]
+pub enum Packet {
+    Range(RangeReport),
+    Imu(ImuReport),
+    Cir(CirReport),
+    Version(VersionReport),
+    Unknown { magic: [u8; 3], bytes: Vec<u8> },
+}
+
+impl Packet {
+    /// Parse an rzCOBS-decoded payload into a typed packet
+    ///
+    /// Payloads with an unrecognized magic are returned as `Packet::Unknown`.
+    pub fn parse(decoded: &[u8]) -> BinResult<Packet> {
+        let mut cursor = io::Cursor::new(decoded);
+
+        if decoded.len() < 3 {
+            return Err(Error::AssertFail {
+                pos: 0,
+                message: format!("packet too short: {} bytes", decoded.len()),
+            });
+        }
+
+        let magic = [decoded[0], decoded[1], decoded[2]];
+        let packet = match &magic {
+            b"RNG" => Packet::Range(RangeReport::read(&mut cursor)?),
+            b"IMU" => Packet::Imu(ImuReport::read(&mut cursor)?),
+            b"CIR" => Packet::Cir(CirReport::read(&mut cursor)?),
+            b"VER" => Packet::Version(VersionReport::read(&mut cursor)?),
+            _ => Packet::Unknown {
+                magic,
+                bytes: decoded.to_vec(),
+            },
+        };
+
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_layout() {
+        let commands = [
+            (
+                Command::SetRangingRate { rate_hz: 10 },
+                &b"CMD\x01\x0a\x00"[..],
+            ),
+            (Command::SetCirDump { enable: true }, &b"CMD\x02\x01"[..]),
+            (Command::GetVersion, &b"CMD\x03"[..]),
+            (Command::Reboot, &b"CMD\x04"[..]),
+        ];
+
+        for (command, bytes) in commands {
+            let mut cursor = io::Cursor::new(Vec::new());
+            command.write(&mut cursor).unwrap();
+            assert_eq!(cursor.get_ref().as_slice(), bytes);
+
+            let decoded = Command::read(&mut io::Cursor::new(bytes)).unwrap();
+            assert_eq!(decoded, command);
+        }
+    }
+
+    #[test]
+    fn test_command_json() {
+        let command: Command =
+            serde_json::from_str(r#"{"command": "set_ranging_rate", "rate_hz": 20}"#).unwrap();
+        assert_eq!(command, Command::SetRangingRate { rate_hz: 20 });
+
+        let command: Command = serde_json::from_str(r#"{"command": "reboot"}"#).unwrap();
+        assert_eq!(command, Command::Reboot);
+    }
+}
@@ -0,0 +1,273 @@
+// Supervised serial ports
+//
+// A serial source opens its device and yields the decoded items. On a read
+// error or a disconnection it reopens the device with an exponential backoff
+// instead of ending, so the other ports keep running. The low latency mode is
+// enabled on every (re)connection, and the new write half is handed over to
+// the downlink.
+
+use std::{io, time::Duration};
+
+use clap::ValueEnum;
+use futures::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+use tokio_util::codec::{Decoder, FramedRead};
+use tracing::{error, info, warn};
+
+use crate::port_selector::PortSelector;
+
+/// Baud rate of the serial ports when not configured
+pub const DEFAULT_BAUD_RATE: u32 = 921600;
+
+/// Flow control of a serial port
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    #[default]
+    None,
+    /// XON/XOFF
+    Software,
+    /// RTS/CTS
+    Hardware,
+}
+
+impl From<FlowControl> for tokio_serial::FlowControl {
+    fn from(flow_control: FlowControl) -> Self {
+        match flow_control {
+            FlowControl::None => tokio_serial::FlowControl::None,
+            FlowControl::Software => tokio_serial::FlowControl::Software,
+            FlowControl::Hardware => tokio_serial::FlowControl::Hardware,
+        }
+    }
+}
+
+/// Settings to open a serial port
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortSettings {
+    /// Device, resolved on every connection
+    pub port: PortSelector,
+    pub baud_rate: u32,
+    pub flow_control: FlowControl,
+    /// Read and write timeout
+    pub timeout: Duration,
+    /// Enable the low latency mode of the USB serial adapter
+    pub low_latency: bool,
+}
+
+impl PortSettings {
+    pub fn new(port: PortSelector, baud_rate: u32) -> Self {
+        PortSettings {
+            port,
+            baud_rate,
+            flow_control: FlowControl::None,
+            timeout: Duration::from_millis(10),
+            low_latency: true,
+        }
+    }
+}
+
+/// Open a serial port, and drop the bytes received before
+pub fn open(settings: &PortSettings) -> Result<SerialStream, tokio_serial::Error> {
+    let path = settings
+        .port
+        .resolve()
+        .map_err(|e| tokio_serial::Error::new(tokio_serial::ErrorKind::NoDevice, e.to_string()))?;
+
+    let builder = tokio_serial::new(&path, settings.baud_rate)
+        .flow_control(settings.flow_control.into())
+        .timeout(settings.timeout);
+
+    if settings.low_latency {
+        // The low latency mode is set on a blocking handle of the port
+        let mut port = builder.clone().open_native()?;
+        if let Err(e) = serialport_low_latency::enable_low_latency(&mut port) {
+            warn!(
+                "Error enabling low latency on serial port {}: {:?}",
+                path, e
+            );
+        }
+    }
+
+    let port = builder.open_native_async()?;
+    port.clear(tokio_serial::ClearBuffer::Input)?;
+
+    Ok(port)
+}
+
+/// Options of the reconnection of the serial ports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectOptions {
+    /// Delay before the first attempt to reopen a port
+    pub initial_backoff: Duration,
+    /// Longest delay between the attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        ReconnectOptions {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Exponential backoff between the attempts to reopen a port
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    options: ReconnectOptions,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(options: ReconnectOptions) -> Self {
+        Backoff {
+            options,
+            next: options.initial_backoff,
+        }
+    }
+
+    /// Delay before the next attempt, doubling up to the maximum
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.options.max_backoff);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.options.initial_backoff;
+    }
+}
+
+/// Event of a supervised serial port
+#[derive(Debug)]
+pub enum PortEvent<T> {
+    /// The port was opened, with its write half for the downlink
+    Connected(WriteHalf<SerialStream>),
+    /// The port failed, and is being reopened
+    Disconnected(io::Error),
+    /// Item decoded from the port
+    Item(T),
+}
+
+struct Source<D, F> {
+    settings: PortSettings,
+    backoff: Backoff,
+    make_decoder: F,
+    reader: Option<FramedRead<ReadHalf<SerialStream>, D>>,
+    opened: bool,
+}
+
+impl<D, F> Source<D, F>
+where
+    D: Decoder<Error = io::Error>,
+    F: FnMut() -> D,
+{
+    async fn next_event(&mut self) -> PortEvent<D::Item> {
+        loop {
+            let Some(reader) = self.reader.as_mut() else {
+                // Wait before reopening, the device may still be going away
+                if self.opened {
+                    tokio::time::sleep(self.backoff.next_delay()).await;
+                }
+                self.opened = true;
+
+                match open(&self.settings) {
+                    Ok(port) => {
+                        info!("Serial port {} opened", self.settings.port);
+                        let (reader, writer) = tokio::io::split(port);
+                        self.reader = Some(FramedRead::new(reader, (self.make_decoder)()));
+                        return PortEvent::Connected(writer);
+                    }
+                    Err(e) => {
+                        warn!("Error opening serial port {}: {}", self.settings.port, e);
+                        continue;
+                    }
+                }
+            };
+
+            match reader.next().await {
+                Some(Ok(item)) => {
+                    self.backoff.reset();
+                    return PortEvent::Item(item);
+                }
+                Some(Err(e)) => {
+                    error!(
+                        "Error reading from serial port {}: {}",
+                        self.settings.port, e
+                    );
+                    self.reader = None;
+                    return PortEvent::Disconnected(e);
+                }
+                None => {
+                    error!("Serial port {} closed", self.settings.port);
+                    self.reader = None;
+                    return PortEvent::Disconnected(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+    }
+}
+
+/// Supervised serial port, decoding with a new decoder from `make_decoder` on
+/// every connection
+///
+/// The stream never ends: the port is reopened after any error.
+pub fn supervised<D, F>(
+    settings: PortSettings,
+    reconnect: ReconnectOptions,
+    make_decoder: F,
+) -> impl Stream<Item = PortEvent<D::Item>>
+where
+    D: Decoder<Error = io::Error>,
+    F: FnMut() -> D,
+{
+    let source = Source {
+        settings,
+        backoff: Backoff::new(reconnect),
+        make_decoder,
+        reader: None,
+        opened: false,
+    };
+
+    stream::unfold(source, |mut source| async move {
+        let event = source.next_event().await;
+        Some((event, source))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream_decoder::MagicLocPacketDecoder;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(ReconnectOptions {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        });
+
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 500, 500]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_missing_device() {
+        let settings = PortSettings::new(PortSelector::Path("/dev/does-not-exist".into()), 921600);
+        assert!(open(&settings).is_err());
+
+        // The source keeps retrying instead of failing
+        let source = supervised(settings, ReconnectOptions::default(), || {
+            MagicLocPacketDecoder::default()
+        });
+        let mut source = Box::pin(source);
+        let next = tokio::time::timeout(Duration::from_millis(300), source.next()).await;
+        assert!(next.is_err());
+    }
+}
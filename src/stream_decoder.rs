@@ -4,15 +4,211 @@
 // 1. Get the currently available bytes from the serial port
 // 2. Drop all bytes until a zero byte is found
 // 3. Push all received bytes into the buffer until another zero byte is found
-// 4. Check if the first two bytes are the header bytes [0xFF, 0x01]
+// 4. Check if the first three bytes are the header bytes [0xFF, version, 0x00]
 // 5. If the header bytes are found, return the buffer slice
+//
+// A frame growing beyond the maximum frame length without a terminating zero
+// byte is abandoned, and the decoder resynchronizes on the next zero byte.
+//
+// `MagicLocPacketDecoder` sits on top of the frame decoder, rzCOBS-decodes each
+// frame and dispatches on the magic bytes to yield a typed `proto::Packet`.
+//
+// `TimestampedDecoder` wraps either decoder and tags each item with the host
+// receive time of the read that completed it.
+//
+// The version byte selects an optional CRC trailer, appended (little endian) to
+// the payload before rzCOBS encoding:
+//  - 0x01: no CRC
+//  - 0x02: CRC-16/IBM-SDLC
+//  - 0x03: CRC-32/ISO-HDLC
+//
+// rzCOBS decoding pads the payload with up to 6 zero bytes, so with a CRC the
+// payload is prefixed with its length (u16, little endian) to locate the
+// trailer. The CRC covers the length and the payload:
+//   length | payload | CRC | zero padding
+
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 
+use crc::{Crc, CRC_16_IBM_SDLC, CRC_32_ISO_HDLC};
+use serde::{Deserialize, Serialize};
 use tokio_util::{
     bytes::{Buf, BytesMut},
     codec::Decoder,
 };
+use tracing::{debug, error, warn};
+
+use crate::proto;
+
+/// Default maximum frame length, including the header
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 1024;
+
+const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Frame format version, carried in the header byte following 0xFF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameVersion {
+    Plain = 0x01,
+    Crc16 = 0x02,
+    Crc32 = 0x03,
+}
 
-pub struct MagicLocStreamDecoder;
+impl FrameVersion {
+    pub fn from_byte(byte: u8) -> Option<FrameVersion> {
+        match byte {
+            0x01 => Some(FrameVersion::Plain),
+            0x02 => Some(FrameVersion::Crc16),
+            0x03 => Some(FrameVersion::Crc32),
+            _ => None,
+        }
+    }
+
+    /// Length of the CRC trailer in bytes
+    pub fn trailer_len(self) -> usize {
+        match self {
+            FrameVersion::Plain => 0,
+            FrameVersion::Crc16 => 2,
+            FrameVersion::Crc32 => 4,
+        }
+    }
+
+    /// Compute the CRC trailer for the given bytes
+    pub fn trailer(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            FrameVersion::Plain => Vec::new(),
+            FrameVersion::Crc16 => CRC16.checksum(bytes).to_le_bytes().to_vec(),
+            FrameVersion::Crc32 => CRC32.checksum(bytes).to_le_bytes().to_vec(),
+        }
+    }
+
+    /// Bytes to rzCOBS encode for a payload, with its length and CRC trailer
+    ///
+    /// Panics if a payload with a CRC is longer than 65535 bytes.
+    pub fn wrap(self, payload: &[u8]) -> Vec<u8> {
+        if self == FrameVersion::Plain {
+            return payload.to_vec();
+        }
+
+        let length = u16::try_from(payload.len()).expect("payload too long for a CRC frame");
+        let mut wrapped = length.to_le_bytes().to_vec();
+        wrapped.extend_from_slice(payload);
+        wrapped.extend(self.trailer(&wrapped));
+        wrapped
+    }
+
+    /// Verify the CRC trailer of a decoded payload, and strip the length,
+    /// trailer and padding
+    ///
+    /// Returns `None` if the payload is too short or the CRC does not match.
+    /// Without a CRC, the payload is returned with its padding.
+    pub fn verify(self, decoded: &[u8]) -> Option<&[u8]> {
+        if self == FrameVersion::Plain {
+            return Some(decoded);
+        }
+
+        let length = u16::from_le_bytes([*decoded.first()?, *decoded.get(1)?]) as usize;
+        let end = 2 + length;
+        let trailer = decoded.get(end..end + self.trailer_len())?;
+
+        // Anything after the trailer is the rzCOBS padding
+        let padding = &decoded[end + self.trailer_len()..];
+        if padding.iter().any(|&byte| byte != 0) {
+            return None;
+        }
+
+        if self.trailer(&decoded[..end]) == trailer {
+            Some(&decoded[2..end])
+        } else {
+            None
+        }
+    }
+}
+
+/// Link quality counters collected by the decoders
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DecoderStats {
+    /// Bytes dropped while searching for a frame
+    pub bytes_discarded: u64,
+    /// Frames starting with a zero byte but not followed by a valid header
+    pub header_mismatches: u64,
+    /// Frames whose payload is not valid rzCOBS
+    pub rzcobs_errors: u64,
+    /// Frames dropped because of a CRC mismatch
+    pub crc_errors: u64,
+    /// Frames with a known magic that could not be parsed
+    pub parse_errors: u64,
+    /// Frames abandoned for exceeding the maximum frame length
+    pub oversized_frames: u64,
+    /// Frames yielded by the frame decoder
+    pub frames_emitted: u64,
+    /// Largest frame seen so far, including the header
+    pub max_frame_size: usize,
+}
+
+/// Shared handle to the statistics of a decoder
+///
+/// The handle stays valid after the decoder has been moved into a `Framed`.
+#[derive(Default, Debug, Clone)]
+pub struct DecoderStatsHandle(Arc<Mutex<DecoderStats>>);
+
+impl DecoderStatsHandle {
+    /// Get a copy of the current counters
+    pub fn snapshot(&self) -> DecoderStats {
+        *self.0.lock().unwrap()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut DecoderStats)) {
+        f(&mut self.0.lock().unwrap());
+    }
+}
+
+pub struct MagicLocStreamDecoder {
+    stats: DecoderStatsHandle,
+    max_frame_length: usize,
+}
+
+impl Default for MagicLocStreamDecoder {
+    fn default() -> Self {
+        MagicLocStreamDecoder {
+            stats: DecoderStatsHandle::default(),
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+        }
+    }
+}
+
+impl MagicLocStreamDecoder {
+    /// Set the maximum frame length, including the header
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.max_frame_length = max_frame_length;
+        self
+    }
+
+    /// Collect the statistics in an existing handle, e.g. across reconnections
+    pub fn with_stats_handle(mut self, stats: DecoderStatsHandle) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Get a handle to the decoder statistics
+    pub fn stats_handle(&self) -> DecoderStatsHandle {
+        self.stats.clone()
+    }
+
+    fn abandon_frame(&self, src: &mut BytesMut, length: usize) {
+        warn!(
+            "Frame exceeds {} bytes, dropping {} bytes",
+            self.max_frame_length, length
+        );
+        self.stats.update(|s| {
+            s.oversized_frames += 1;
+            s.bytes_discarded += length as u64;
+        });
+        src.advance(length);
+    }
+}
 
 impl Decoder for MagicLocStreamDecoder {
     type Item = Vec<u8>;
@@ -36,9 +232,13 @@ impl Decoder for MagicLocStreamDecoder {
             }
         }
         if !zero_byte_found {
+            self.stats
+                .update(|s| s.bytes_discarded += available_bytes as u64);
             src.clear();
             return Ok(None);
         }
+        self.stats
+            .update(|s| s.bytes_discarded += zero_byte_index as u64);
         src.advance(zero_byte_index); // Drop all bytes until the zero byte
 
         // at this point, the first byte is a zero byte
@@ -47,8 +247,14 @@ impl Decoder for MagicLocStreamDecoder {
             return Ok(None);
         }
 
-        // Check if the first three non-zero bytes are the header bytes [0xFF, 0x01, 0x00]
-        if src[1] != 0xFF || src[2] != 0x01 || src[3] != 0x00 {
+        // Check if the first three non-zero bytes are the header bytes [0xFF, version, 0x00]
+        if src[1] != 0xFF || FrameVersion::from_byte(src[2]).is_none() || src[3] != 0x00 {
+            if src[1] == 0x00 {
+                // Consecutive delimiters (end of the last frame, start of the next one)
+                src.advance(1);
+                return Ok(None);
+            }
+
             // This is not a valid packet, drop til the next zero byte
             let mut zero_byte_found = false;
             let mut zero_byte_index = 0;
@@ -59,12 +265,16 @@ impl Decoder for MagicLocStreamDecoder {
                     break;
                 }
             }
-            if !zero_byte_found {
-                src.clear();
-                return Ok(None);
+            let dropped = if zero_byte_found {
+                zero_byte_index
             } else {
-                src.advance(zero_byte_index); // Drop all bytes until the zero byte
-            }
+                src.len()
+            };
+            self.stats.update(|s| {
+                s.header_mismatches += 1;
+                s.bytes_discarded += dropped as u64;
+            });
+            src.advance(dropped); // Drop all bytes until the zero byte
             return Ok(None);
         }
 
@@ -79,22 +289,193 @@ impl Decoder for MagicLocStreamDecoder {
             }
         }
         if !zero_byte_found {
+            if src.len() > self.max_frame_length {
+                // No zero byte after the header, so drop everything we have
+                self.abandon_frame(src, src.len());
+            }
+            return Ok(None);
+        }
+        if zero_byte_index + 4 > self.max_frame_length {
+            // Drop the frame, keeping its terminating zero byte
+            self.abandon_frame(src, zero_byte_index + 4);
             return Ok(None);
         }
 
         // At this point, we have a buffer with 0x00FF0100...0x00
         let result = src.split_to(zero_byte_index + 4);
+        self.stats.update(|s| {
+            s.frames_emitted += 1;
+            s.max_frame_size = s.max_frame_size.max(result.len());
+        });
         Ok(Some(result.to_vec()))
     }
 }
 
+/// Decoder yielding typed packets instead of raw frames
+#[derive(Default)]
+pub struct MagicLocPacketDecoder {
+    frame_decoder: MagicLocStreamDecoder,
+}
+
+impl MagicLocPacketDecoder {
+    /// Set the maximum frame length, including the header
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.frame_decoder = self.frame_decoder.with_max_frame_length(max_frame_length);
+        self
+    }
+
+    /// Collect the statistics in an existing handle, e.g. across reconnections
+    pub fn with_stats_handle(mut self, stats: DecoderStatsHandle) -> Self {
+        self.frame_decoder = self.frame_decoder.with_stats_handle(stats);
+        self
+    }
+
+    /// Get a handle to the decoder statistics, shared with the frame decoder
+    pub fn stats_handle(&self) -> DecoderStatsHandle {
+        self.frame_decoder.stats_handle()
+    }
+}
+
+impl Decoder for MagicLocPacketDecoder {
+    type Item = proto::Packet;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            let available_bytes = src.len();
+
+            let frame = match self.frame_decoder.decode(src)? {
+                Some(frame) => frame,
+                // Keep going as long as the frame decoder is making progress,
+                // otherwise wait for more bytes from the serial port
+                None if src.len() < available_bytes => continue,
+                None => return Ok(None),
+            };
+
+            // The frame decoder only yields frames with a known version
+            let version = FrameVersion::from_byte(frame[2]).unwrap();
+
+            // Skip the 0x00 0xFF version 0x00 header
+            let decoded = match rzcobs::decode(&frame[4..]) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    debug!("Decoding error: {:?}", e);
+                    self.frame_decoder.stats.update(|s| s.rzcobs_errors += 1);
+                    continue;
+                }
+            };
+
+            let Some(payload) = version.verify(&decoded) else {
+                let stats = &self.frame_decoder.stats;
+                stats.update(|s| s.crc_errors += 1);
+                warn!(
+                    "CRC mismatch ({:?}), dropping frame ({} dropped so far)",
+                    version,
+                    stats.snapshot().crc_errors
+                );
+                continue;
+            };
+
+            match proto::Packet::parse(payload) {
+                Ok(packet) => return Ok(Some(packet)),
+                Err(e) => {
+                    error!("Malformed packet {:?}: {}", decoded, e);
+                    self.frame_decoder.stats.update(|s| s.parse_errors += 1);
+                    continue;
+                }
+            }
+        }
+    }
+}
+
+/// Host receive time of a frame
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReceiveTime {
+    /// Monotonic clock, in seconds since the first receive time of the process
+    pub rx_monotonic: f64,
+    /// Wall clock, in seconds since the UNIX epoch
+    pub rx_wall: f64,
+}
+
+impl ReceiveTime {
+    pub fn now() -> Self {
+        static START: OnceLock<Instant> = OnceLock::new();
+        let monotonic = Instant::now();
+        let wall = SystemTime::now();
+
+        ReceiveTime {
+            rx_monotonic: monotonic
+                .duration_since(*START.get_or_init(|| monotonic))
+                .as_secs_f64(),
+            rx_wall: wall
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs_f64())
+                .unwrap_or(0.0),
+        }
+    }
+}
+
+/// Item of a decoder with its host receive time
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Received<T> {
+    #[serde(flatten)]
+    pub time: ReceiveTime,
+    #[serde(flatten)]
+    pub item: T,
+}
+
+/// Decoder tagging the items of another decoder with their host receive time
+///
+/// The time is taken when new bytes show up in the buffer, right after the read,
+/// so the items completed by the same read share it.
+pub struct TimestampedDecoder<D> {
+    inner: D,
+    buffered: usize,
+    read_time: ReceiveTime,
+}
+
+impl<D> TimestampedDecoder<D> {
+    pub fn new(inner: D) -> Self {
+        TimestampedDecoder {
+            inner,
+            buffered: 0,
+            read_time: ReceiveTime::now(),
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+}
+
+impl<D: Decoder> Decoder for TimestampedDecoder<D> {
+    type Item = Received<D::Item>;
+    type Error = D::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() > self.buffered {
+            self.read_time = ReceiveTime::now();
+        }
+
+        let item = self.inner.decode(src);
+        self.buffered = src.len();
+
+        Ok(item?.map(|item| Received {
+            time: self.read_time,
+            item,
+        }))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stream_encoder::{encode_frame, encode_payload};
+    use binrw::BinWrite;
 
     #[test]
     fn test_decoder() {
-        let mut decoder = MagicLocStreamDecoder {};
+        let mut decoder = MagicLocStreamDecoder::default();
         let mut buffer = BytesMut::new();
 
         // Test 1: empty buffer
@@ -151,7 +532,7 @@ mod tests {
 
     #[test]
     fn test_real_data() {
-        let mut decoder = MagicLocStreamDecoder {};
+        let mut decoder = MagicLocStreamDecoder::default();
 
         let mut buffer = BytesMut::new();
         buffer.extend_from_slice(&[
@@ -186,4 +567,228 @@ mod tests {
         let _ = decoder.decode(&mut buffer);
         let _ = decoder.decode(&mut buffer);
     }
+
+    #[test]
+    fn test_packet_decoder() {
+        let mut decoder = MagicLocPacketDecoder::default();
+
+        let range_report = proto::RangeReport {
+            tag_addr: 0x1234,
+            system_ts: 1000,
+            seq_num: 7,
+            trigger_txts: 0x0102_0000,
+            ranges: [1.5, 2.0, 0.5, 4.0, f64::NAN, 3.0, 2.5, 1.0],
+        };
+        let imu_report = proto::ImuReport {
+            tag_addr: 0x1234,
+            system_ts: 2000,
+            accel: [1, 2, 3],
+            gyro: [4, 5, 6],
+        };
+
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&[0x11, 0x22, 0x33]); // garbage before the first frame
+        buffer.extend_from_slice(&encode_frame(&range_report, FrameVersion::Plain).unwrap());
+        buffer.extend_from_slice(&encode_frame(&imu_report, FrameVersion::Plain).unwrap());
+
+        match decoder.decode(&mut buffer).unwrap() {
+            Some(proto::Packet::Range(decoded)) => {
+                assert_eq!(decoded.tag_addr, range_report.tag_addr);
+                assert_eq!(decoded.trigger_txts, range_report.trigger_txts);
+                assert_eq!(decoded.ranges[0], 1.5);
+                assert!(decoded.ranges[4].is_nan());
+            }
+            other => panic!("Expected a range report, got {:?}", other),
+        }
+
+        match decoder.decode(&mut buffer).unwrap() {
+            Some(proto::Packet::Imu(decoded)) => assert_eq!(decoded, imu_report),
+            other => panic!("Expected an IMU report, got {:?}", other),
+        }
+
+        assert!(decoder.decode(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_packet_decoder_unknown_magic() {
+        let mut decoder = MagicLocPacketDecoder::default();
+
+        let payload = b"XYZ\x00\x01\x00\x02";
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&encode_payload(payload, FrameVersion::Plain));
+
+        match decoder.decode(&mut buffer).unwrap() {
+            Some(proto::Packet::Unknown { magic, bytes }) => {
+                assert_eq!(&magic, b"XYZ");
+                assert_eq!(&bytes[..], payload);
+            }
+            other => panic!("Expected an unknown packet, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_crc_check_values() {
+        assert_eq!(FrameVersion::Crc16.trailer(b"123456789"), [0x6E, 0x90]);
+        assert_eq!(
+            FrameVersion::Crc32.trailer(b"123456789"),
+            [0x26, 0x39, 0xF4, 0xCB]
+        );
+        assert!(FrameVersion::Plain.trailer(b"123456789").is_empty());
+    }
+
+    #[test]
+    fn test_crc_padding() {
+        // Payload lengths with every amount of rzCOBS padding
+        for length in 0..16 {
+            let payload: Vec<u8> = (1..=length).collect();
+            for version in [FrameVersion::Crc16, FrameVersion::Crc32] {
+                let decoded = rzcobs::decode(&rzcobs::encode(&version.wrap(&payload))).unwrap();
+                assert_eq!(version.verify(&decoded), Some(&payload[..]));
+
+                // A truncated length does not pass
+                let mut corrupted = decoded.clone();
+                corrupted[0] = corrupted[0].wrapping_sub(1);
+                assert_eq!(version.verify(&corrupted), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_packet_decoder_crc() {
+        let imu_report = proto::ImuReport {
+            tag_addr: 0x0042,
+            system_ts: 123456,
+            accel: [10, 0, 20],
+            gyro: [0, 30, 0],
+        };
+
+        for version in [FrameVersion::Crc16, FrameVersion::Crc32] {
+            let mut decoder = MagicLocPacketDecoder::default();
+
+            // A valid frame is decoded
+            let mut buffer = BytesMut::new();
+            buffer.extend_from_slice(&encode_frame(&imu_report, version).unwrap());
+            match decoder.decode(&mut buffer).unwrap() {
+                Some(proto::Packet::Imu(decoded)) => assert_eq!(decoded, imu_report),
+                other => panic!("Expected an IMU report, got {:?}", other),
+            }
+            assert_eq!(decoder.stats_handle().snapshot().crc_errors, 0);
+
+            // A corrupted frame is dropped and counted
+            let mut payload = binrw::io::Cursor::new(Vec::new());
+            imu_report
+                .write_options(&mut payload, binrw::Endian::Little, ())
+                .unwrap();
+            let mut payload = version.wrap(&payload.into_inner());
+            payload[10] ^= 0x10; // Bit flip in system_ts, after the length
+
+            let mut buffer = BytesMut::new();
+            buffer.extend_from_slice(&[0x00, 0xFF, version as u8, 0x00]);
+            buffer.extend_from_slice(&rzcobs::encode(&payload));
+            buffer.extend_from_slice(&[0x00]);
+            assert!(decoder.decode(&mut buffer).unwrap().is_none());
+            assert_eq!(decoder.stats_handle().snapshot().crc_errors, 1);
+        }
+    }
+
+    #[test]
+    fn test_decoder_stats() {
+        let mut decoder = MagicLocStreamDecoder::default();
+        let stats = decoder.stats_handle();
+
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&[0x11, 0x22, 0x00, 0x01, 0x02, 0x03]);
+        buffer.extend_from_slice(&[0x00, 0xFF, 0x01, 0x00, 0x02, 0x03, 0x00]);
+        buffer.extend_from_slice(&[0x00, 0xFF, 0x01, 0x00, 0x04, 0x05, 0x06, 0x00]);
+
+        let mut frames = Vec::new();
+        for _ in 0..10 {
+            if let Some(frame) = decoder.decode(&mut buffer).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames.len(), 2);
+
+        // A new decoder can keep counting in the same handle
+        let mut decoder = MagicLocStreamDecoder::default().with_stats_handle(stats.clone());
+        buffer.extend_from_slice(&[0x00, 0xFF, 0x01, 0x00, 0x02, 0x00]);
+        for _ in 0..2 {
+            if let Some(frame) = decoder.decode(&mut buffer).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames.len(), 3);
+
+        let stats = stats.snapshot();
+        assert_eq!(stats.bytes_discarded, 6);
+        assert_eq!(stats.header_mismatches, 1);
+        assert_eq!(stats.frames_emitted, 3);
+        assert_eq!(stats.max_frame_size, 7);
+    }
+
+    #[test]
+    fn test_max_frame_length() {
+        let mut decoder = MagicLocStreamDecoder::default().with_max_frame_length(8);
+        let stats = decoder.stats_handle();
+
+        // A header followed by garbage without a terminating zero
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&[0x00, 0xFF, 0x01, 0x00, 0x01, 0x02, 0x03]);
+        assert!(decoder.decode(&mut buffer).unwrap().is_none());
+        assert_eq!(buffer.len(), 7);
+
+        buffer.extend_from_slice(&[0x04, 0x05]);
+        assert!(decoder.decode(&mut buffer).unwrap().is_none());
+        assert!(buffer.is_empty());
+        assert_eq!(stats.snapshot().oversized_frames, 1);
+
+        // The decoder resynchronizes on the next frame
+        buffer.extend_from_slice(&[0x06, 0x07, 0x00, 0xFF, 0x01, 0x00, 0x02, 0x03, 0x00]);
+        let mut frames = Vec::new();
+        for _ in 0..4 {
+            if let Some(frame) = decoder.decode(&mut buffer).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames, [vec![0x00, 0xFF, 0x01, 0x00, 0x02, 0x03]]);
+
+        // A complete but oversized frame is dropped as well
+        buffer.clear();
+        buffer.extend_from_slice(&[0x00, 0xFF, 0x01, 0x00, 1, 2, 3, 4, 5, 6, 0x00]);
+        assert!(decoder.decode(&mut buffer).unwrap().is_none());
+        assert_eq!(&buffer[..], [0x00]);
+        assert_eq!(stats.snapshot().oversized_frames, 2);
+    }
+
+    #[test]
+    fn test_timestamped_decoder() {
+        let mut decoder = TimestampedDecoder::new(MagicLocStreamDecoder::default());
+
+        // Two frames in the same read share the receive time
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&[0x00, 0xFF, 0x01, 0x00, 0x02, 0x00, 0xFF, 0x01, 0x00, 0x03]);
+        buffer.extend_from_slice(&[0x00, 0xFF, 0x01, 0x00, 0x04]);
+        let first = decoder.decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(first.item, [0x00, 0xFF, 0x01, 0x00, 0x02]);
+        let second = decoder.decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(second.item, [0x00, 0xFF, 0x01, 0x00, 0x03]);
+        assert_eq!(first.time, second.time);
+        assert!(decoder.decode(&mut buffer).unwrap().is_none());
+        assert!(first.time.rx_wall > 0.0);
+
+        // The frame completed by a later read gets its time
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        buffer.extend_from_slice(&[0x00]);
+        let third = decoder.decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(third.item, [0x00, 0xFF, 0x01, 0x00, 0x04]);
+        assert!(third.time.rx_monotonic >= first.time.rx_monotonic + 1e-3);
+        assert!(third.time.rx_wall > first.time.rx_wall);
+
+        let json = serde_json::to_string(&Received {
+            time: third.time,
+            item: DecoderStats::default(),
+        })
+        .unwrap();
+        assert!(json.contains("\"rx_monotonic\"") && json.contains("\"frames_emitted\""));
+    }
 }
@@ -0,0 +1,169 @@
+// Encoder for the custom wire format.
+//
+// Produces frames understood by `MagicLocStreamDecoder`:
+//   0x00 0xFF version 0x00 | rzCOBS([length] | payload | [CRC trailer]) | 0x00
+//
+// The payload is the binrw serialization of a `proto` struct, including its magic.
+
+use std::io;
+
+use binrw::BinWrite;
+use tokio_util::{bytes::BytesMut, codec::Encoder};
+
+use crate::stream_decoder::FrameVersion;
+
+/// Serialize an item and wrap it into a complete frame
+pub fn encode_frame<T>(item: &T, version: FrameVersion) -> binrw::BinResult<Vec<u8>>
+where
+    T: for<'a> BinWrite<Args<'a> = ()>,
+{
+    let mut payload = io::Cursor::new(Vec::new());
+    item.write_options(&mut payload, binrw::Endian::Little, ())?;
+
+    Ok(encode_payload(&payload.into_inner(), version))
+}
+
+/// Wrap an already serialized payload into a complete frame
+pub fn encode_payload(payload: &[u8], version: FrameVersion) -> Vec<u8> {
+    let mut frame = vec![0x00, 0xFF, version as u8, 0x00];
+    frame.extend(rzcobs::encode(&version.wrap(payload)));
+    frame.push(0x00);
+    frame
+}
+
+/// Encoder for `proto` structs, the counterpart of `MagicLocPacketDecoder`
+#[derive(Debug, Clone, Copy)]
+pub struct MagicLocStreamEncoder {
+    version: FrameVersion,
+}
+
+impl Default for MagicLocStreamEncoder {
+    fn default() -> Self {
+        MagicLocStreamEncoder {
+            version: FrameVersion::Plain,
+        }
+    }
+}
+
+impl MagicLocStreamEncoder {
+    pub fn new(version: FrameVersion) -> Self {
+        MagicLocStreamEncoder { version }
+    }
+}
+
+impl<T> Encoder<T> for MagicLocStreamEncoder
+where
+    T: for<'a> BinWrite<Args<'a> = ()>,
+{
+    type Error = io::Error;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let frame = encode_frame(&item, self.version)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        dst.extend_from_slice(&frame);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proto, stream_decoder::MagicLocPacketDecoder};
+    use tokio_util::codec::Decoder;
+
+    #[test]
+    fn test_round_trip() {
+        let range_report = proto::RangeReport {
+            tag_addr: 0x0102,
+            system_ts: 0x1000,
+            seq_num: 42,
+            trigger_txts: 0x0203_0000,
+            ranges: [1.5, 2.0, 0.5, 4.0, 8.0, 3.0, 2.5, 1.0],
+        };
+        let imu_report = proto::ImuReport {
+            tag_addr: 0x0102,
+            system_ts: 0x2000,
+            accel: [1, 0, 1000],
+            gyro: [0, 65536, 3],
+        };
+        let mut cir_report = proto::CirReport {
+            src_addr: 0x0304,
+            system_ts: 0x3000,
+            seq_num: 43,
+            ip_poa: 0x1100,
+            fp_index: 0x0040,
+            start_index: 0x0030,
+            cir_size: 16,
+            cir: [proto::RawCirSample::default(); 16],
+        };
+        for (i, sample) in cir_report.cir.iter_mut().enumerate() {
+            sample.real = [i as u8, 0, 0x80];
+            sample.imag = [0, i as u8, 0];
+        }
+
+        for version in [
+            FrameVersion::Plain,
+            FrameVersion::Crc16,
+            FrameVersion::Crc32,
+        ] {
+            let mut encoder = MagicLocStreamEncoder::new(version);
+            let mut buffer = BytesMut::new();
+            encoder.encode(range_report, &mut buffer).unwrap();
+            encoder.encode(imu_report, &mut buffer).unwrap();
+            encoder.encode(cir_report, &mut buffer).unwrap();
+
+            let mut decoder = MagicLocPacketDecoder::default();
+            match decoder.decode(&mut buffer).unwrap() {
+                Some(proto::Packet::Range(decoded)) => assert_eq!(decoded, range_report),
+                other => panic!("Expected a range report, got {:?}", other),
+            }
+            match decoder.decode(&mut buffer).unwrap() {
+                Some(proto::Packet::Imu(decoded)) => assert_eq!(decoded, imu_report),
+                other => panic!("Expected an IMU report, got {:?}", other),
+            }
+            match decoder.decode(&mut buffer).unwrap() {
+                Some(proto::Packet::Cir(decoded)) => {
+                    assert_eq!(decoded.src_addr, cir_report.src_addr);
+                    assert_eq!(decoded.system_ts, cir_report.system_ts);
+                    assert_eq!(decoded.ip_poa, cir_report.ip_poa);
+                    assert_eq!(decoded.cir, cir_report.cir);
+                }
+                other => panic!("Expected a CIR report, got {:?}", other),
+            }
+            assert!(decoder.decode(&mut buffer).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        for version in [
+            FrameVersion::Plain,
+            FrameVersion::Crc16,
+            FrameVersion::Crc32,
+        ] {
+            let frame = encode_payload(b"ABC\0", version);
+
+            assert_eq!(&frame[..4], [0x00, 0xFF, version as u8, 0x00]);
+            assert_eq!(frame.last(), Some(&0x00));
+            assert!(!frame[4..frame.len() - 1].contains(&0x00));
+
+            let mut buffer = BytesMut::from(&frame[..]);
+            match MagicLocPacketDecoder::default()
+                .decode(&mut buffer)
+                .unwrap()
+            {
+                Some(proto::Packet::Unknown { magic, bytes }) => {
+                    assert_eq!(&magic, b"ABC");
+                    if version == FrameVersion::Plain {
+                        // Without a CRC, the rzCOBS padding is left to the parser
+                        assert!(bytes.starts_with(b"ABC\0"));
+                        assert!(bytes[4..].iter().all(|&byte| byte == 0));
+                    } else {
+                        assert_eq!(bytes, b"ABC\0");
+                    }
+                }
+                other => panic!("Expected an unknown packet, got {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+// Anchor self-survey
+//
+// Solves for the anchor coordinates from inter-anchor ranges, up to a rigid transform:
+// 1. Complete the distance matrix (shortest paths for the missing pairs)
+// 2. Classical MDS for the initial coordinates
+// 3. Levenberg-Marquardt refinement on the measured ranges
+// 4. Express the coordinates in a frame defined by three anchors
+
+use std::fmt;
+
+use nalgebra::{DMatrix, DVector, SymmetricEigen, Vector3};
+
+/// Error while surveying the anchors
+#[derive(Debug, Clone, PartialEq)]
+pub enum SurveyError {
+    /// At least four anchors are needed to solve in 3D
+    TooFewAnchors(usize),
+    /// Some anchors are not connected to the others by any range
+    Disconnected,
+    /// The frame anchors are (nearly) collinear
+    DegenerateFrame,
+}
+
+impl fmt::Display for SurveyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SurveyError::TooFewAnchors(n) => write!(f, "too few anchors: {}", n),
+            SurveyError::Disconnected => write!(f, "anchors are not all connected by ranges"),
+            SurveyError::DegenerateFrame => write!(f, "frame anchors are collinear"),
+        }
+    }
+}
+
+impl std::error::Error for SurveyError {}
+
+/// Range measurement between two anchors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterAnchorRange {
+    pub from: usize,
+    pub to: usize,
+    pub range: f64,
+}
+
+/// Collects the ranges between anchors
+pub struct RangeSurvey {
+    anchors: usize,
+    /// All ranges per unordered pair, indexed by `[min][max]`
+    ranges: Vec<Vec<Vec<f64>>>,
+}
+
+impl RangeSurvey {
+    pub fn new(anchors: usize) -> Self {
+        RangeSurvey {
+            anchors,
+            ranges: vec![vec![Vec::new(); anchors]; anchors],
+        }
+    }
+
+    pub fn anchors(&self) -> usize {
+        self.anchors
+    }
+
+    /// Add a range measured between anchors `from` and `to`
+    ///
+    /// Invalid ranges and ranges of an anchor to itself are ignored.
+    pub fn add(&mut self, from: usize, to: usize, range: f64) {
+        if from == to || from >= self.anchors || to >= self.anchors || !range.is_normal() {
+            return;
+        }
+
+        self.ranges[from.min(to)][from.max(to)].push(range);
+    }
+
+    /// Number of ranges collected between two anchors
+    pub fn samples(&self, from: usize, to: usize) -> usize {
+        self.ranges[from.min(to)][from.max(to)].len()
+    }
+
+    /// Median range of every measured pair
+    pub fn measurements(&self) -> Vec<InterAnchorRange> {
+        let mut measurements = Vec::new();
+
+        for from in 0..self.anchors {
+            for to in from + 1..self.anchors {
+                let mut ranges = self.ranges[from][to].clone();
+                if ranges.is_empty() {
+                    continue;
+                }
+                ranges.sort_by(f64::total_cmp);
+
+                let n = ranges.len();
+                let range = (ranges[(n - 1) / 2] + ranges[n / 2]) / 2.0;
+
+                measurements.push(InterAnchorRange { from, to, range });
+            }
+        }
+
+        measurements
+    }
+}
+
+/// Build the full distance matrix, using shortest paths for the missing pairs
+///
+/// The shortest path is an upper bound of the true distance, which is good enough
+/// for the MDS initialization.
+pub fn complete_distances(
+    anchors: usize,
+    measurements: &[InterAnchorRange],
+) -> Result<DMatrix<f64>, SurveyError> {
+    let mut distances = DMatrix::from_element(anchors, anchors, f64::INFINITY);
+    for i in 0..anchors {
+        distances[(i, i)] = 0.0;
+    }
+    for m in measurements {
+        distances[(m.from, m.to)] = m.range;
+        distances[(m.to, m.from)] = m.range;
+    }
+
+    // Floyd-Warshall
+    for k in 0..anchors {
+        for i in 0..anchors {
+            for j in 0..anchors {
+                let through_k = distances[(i, k)] + distances[(k, j)];
+                if through_k < distances[(i, j)] {
+                    distances[(i, j)] = through_k;
+                }
+            }
+        }
+    }
+
+    if distances.iter().any(|d| !d.is_finite()) {
+        return Err(SurveyError::Disconnected);
+    }
+
+    Ok(distances)
+}
+
+/// Classical multidimensional scaling of a distance matrix into 3D
+pub fn classical_mds(distances: &DMatrix<f64>) -> Vec<Vector3<f64>> {
+    let n = distances.nrows();
+
+    // Double centering of the squared distances
+    let squared = distances.map(|d| d * d);
+    let centering = DMatrix::identity(n, n) - DMatrix::from_element(n, n, 1.0 / n as f64);
+    let gram = -0.5 * &centering * squared * &centering;
+
+    let eigen = SymmetricEigen::new(gram);
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| eigen.eigenvalues[b].total_cmp(&eigen.eigenvalues[a]));
+
+    let mut positions = vec![Vector3::zeros(); n];
+    for (axis, &k) in order.iter().take(3).enumerate() {
+        let scale = eigen.eigenvalues[k].max(0.0).sqrt();
+        for (i, position) in positions.iter_mut().enumerate() {
+            position[axis] = eigen.eigenvectors[(i, k)] * scale;
+        }
+    }
+
+    positions
+}
+
+fn survey_cost(positions: &[Vector3<f64>], measurements: &[InterAnchorRange]) -> f64 {
+    measurements
+        .iter()
+        .map(|m| ((positions[m.from] - positions[m.to]).norm() - m.range).powi(2))
+        .sum()
+}
+
+/// Refine the anchor coordinates on the measured ranges (Levenberg-Marquardt)
+///
+/// The problem has a 6 DoF gauge freedom, which the damping keeps well posed.
+pub fn refine_positions(
+    initial: &[Vector3<f64>],
+    measurements: &[InterAnchorRange],
+    max_iterations: usize,
+) -> Vec<Vector3<f64>> {
+    let n = initial.len();
+    let mut positions = initial.to_vec();
+    let mut cost = survey_cost(&positions, measurements);
+    let mut lambda = 1e-3;
+
+    for _ in 0..max_iterations {
+        let mut jacobian = DMatrix::zeros(measurements.len(), 3 * n);
+        let mut residuals = DVector::zeros(measurements.len());
+
+        for (k, m) in measurements.iter().enumerate() {
+            let diff = positions[m.from] - positions[m.to];
+            let dist = diff.norm().max(1e-9);
+            let direction = diff / dist;
+
+            for axis in 0..3 {
+                jacobian[(k, 3 * m.from + axis)] = direction[axis];
+                jacobian[(k, 3 * m.to + axis)] = -direction[axis];
+            }
+            residuals[k] = dist - m.range;
+        }
+
+        let jtj = jacobian.transpose() * &jacobian;
+        let jtr = jacobian.transpose() * &residuals;
+
+        // Increase the damping until the step decreases the cost
+        let mut improved = false;
+        while lambda < 1e10 {
+            let damped = &jtj + DMatrix::identity(3 * n, 3 * n) * lambda;
+            let Some(step) = damped.cholesky().map(|c| c.solve(&(-&jtr))) else {
+                lambda *= 10.0;
+                continue;
+            };
+
+            let candidate: Vec<Vector3<f64>> = positions
+                .iter()
+                .enumerate()
+                .map(|(i, p)| p + step.fixed_rows::<3>(3 * i))
+                .collect();
+            let candidate_cost = survey_cost(&candidate, measurements);
+
+            if candidate_cost < cost {
+                let converged = step.norm() < 1e-9 || cost - candidate_cost < 1e-14;
+                positions = candidate;
+                cost = candidate_cost;
+                lambda = (lambda / 10.0).max(1e-12);
+                improved = !converged;
+                break;
+            }
+            lambda *= 10.0;
+        }
+
+        if !improved {
+            break;
+        }
+    }
+
+    positions
+}
+
+/// Express the coordinates in the frame defined by three anchors
+///
+/// `origin` is placed at the origin, `x_axis` on the positive x axis and `xy_plane`
+/// in the xy plane with a positive y. As the ranges cannot tell a configuration from
+/// its mirror image, the z axis is chosen such that the other anchors are mostly above
+/// the xy plane.
+pub fn align_frame(
+    positions: &mut [Vector3<f64>],
+    origin: usize,
+    x_axis: usize,
+    xy_plane: usize,
+) -> Result<(), SurveyError> {
+    let center = positions[origin];
+    let x = (positions[x_axis] - center).normalize();
+    let in_plane = positions[xy_plane] - center;
+    let y = in_plane - x * x.dot(&in_plane);
+    if y.norm() < 1e-6 || !x.iter().all(|v| v.is_finite()) {
+        return Err(SurveyError::DegenerateFrame);
+    }
+    let y = y.normalize();
+    let z = x.cross(&y);
+
+    for position in positions.iter_mut() {
+        let p = *position - center;
+        *position = Vector3::new(x.dot(&p), y.dot(&p), z.dot(&p));
+    }
+
+    let height: f64 = positions.iter().map(|p| p.z).sum();
+    if height < 0.0 {
+        positions.iter_mut().for_each(|p| p.z = -p.z);
+    }
+
+    Ok(())
+}
+
+/// Solve for the anchor coordinates from the inter-anchor ranges
+///
+/// `frame` is the `(origin, x_axis, xy_plane)` anchors, see `align_frame`.
+pub fn survey_anchors(
+    anchors: usize,
+    measurements: &[InterAnchorRange],
+    frame: (usize, usize, usize),
+) -> Result<Vec<Vector3<f64>>, SurveyError> {
+    if anchors < 4 {
+        return Err(SurveyError::TooFewAnchors(anchors));
+    }
+
+    let distances = complete_distances(anchors, measurements)?;
+    let initial = classical_mds(&distances);
+    let mut positions = refine_positions(&initial, measurements, 100);
+    align_frame(&mut positions, frame.0, frame.1, frame.2)?;
+
+    Ok(positions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::COORDINATES_TCR;
+
+    fn truth() -> Vec<Vector3<f64>> {
+        COORDINATES_TCR
+            .iter()
+            .map(|&(x, y, z)| Vector3::new(x, y, z))
+            .collect()
+    }
+
+    #[test]
+    fn test_survey_anchors() {
+        let truth = truth();
+        let n = truth.len();
+
+        // All pairs but two are measured, with a small deterministic noise
+        let mut survey = RangeSurvey::new(n);
+        for i in 0..n {
+            for j in 0..n {
+                if (i, j) == (0, 1) || (i, j) == (1, 0) || (i, j) == (5, 6) || (i, j) == (6, 5) {
+                    continue;
+                }
+                for k in 0..5 {
+                    let noise = 0.002 * (k as f64 - 2.0);
+                    survey.add(i, j, (truth[i] - truth[j]).norm() + noise);
+                }
+            }
+        }
+        assert_eq!(survey.samples(2, 3), 10);
+        assert_eq!(survey.samples(0, 1), 0);
+
+        let measurements = survey.measurements();
+        assert_eq!(measurements.len(), n * (n - 1) / 2 - 2);
+
+        let positions = survey_anchors(n, &measurements, (0, 2, 5)).unwrap();
+
+        let mut expected = truth.clone();
+        align_frame(&mut expected, 0, 2, 5).unwrap();
+        for (position, expected) in positions.iter().zip(expected.iter()) {
+            assert!(
+                (position - expected).norm() < 1e-6,
+                "{:?} != {:?}",
+                position,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_survey_errors() {
+        let measurements = [InterAnchorRange {
+            from: 0,
+            to: 1,
+            range: 1.0,
+        }];
+        assert_eq!(
+            survey_anchors(3, &measurements, (0, 1, 2)),
+            Err(SurveyError::TooFewAnchors(3))
+        );
+        assert_eq!(
+            survey_anchors(4, &measurements, (0, 1, 2)),
+            Err(SurveyError::Disconnected)
+        );
+    }
+}
@@ -0,0 +1,272 @@
+// Range report synchronization
+//
+// Groups the range reports of the serial ports triggered by the same
+// transmission (`trigger_txts`). A group is emitted as soon as every port has
+// reported, or has moved on to a later sequence number, and otherwise after a
+// time window with the missing ports flagged.
+
+use std::{collections::VecDeque, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{proto::RangeReport, stream_decoder::ReceiveTime};
+
+/// Options of the synchronizer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SynchronizerOptions {
+    /// Time to wait for the reports of the other ports after the first one
+    pub window: Duration,
+    /// Maximum number of groups waiting for reports, the oldest is emitted beyond
+    pub max_pending: usize,
+}
+
+impl Default for SynchronizerOptions {
+    fn default() -> Self {
+        SynchronizerOptions {
+            window: Duration::from_millis(100),
+            max_pending: 64,
+        }
+    }
+}
+
+/// Range reports of the same trigger, as published on `ranges`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SynchronizedRanges {
+    pub seq_num: u8,
+    pub trigger_txts: u64,
+    /// Reports of the ports that received the trigger, in port order
+    pub reports: Vec<RangeReport>,
+    /// Ports without a report
+    pub missing: Vec<usize>,
+    /// Host receive times of the reports, in the order of `reports`
+    #[serde(default)]
+    pub received: Vec<ReceiveTime>,
+}
+
+impl SynchronizedRanges {
+    /// Ports of the reports, in the order of `reports`
+    pub fn ports(&self) -> Vec<usize> {
+        (0..self.reports.len() + self.missing.len())
+            .filter(|port| !self.missing.contains(port))
+            .collect()
+    }
+}
+
+/// Whether the sequence number `a` is after `b`, with wraparound
+fn seq_after(a: u8, b: u8) -> bool {
+    a != b && a.wrapping_sub(b) < 128
+}
+
+#[derive(Debug, Clone)]
+struct Group {
+    seq_num: u8,
+    trigger_txts: u64,
+    /// Monotonic receive time of the first report, in seconds
+    first_arrival: f64,
+    reports: Vec<Option<(RangeReport, ReceiveTime)>>,
+    /// Ports that reported a later sequence number without this trigger
+    passed: Vec<bool>,
+}
+
+impl Group {
+    fn is_complete(&self) -> bool {
+        self.reports
+            .iter()
+            .zip(&self.passed)
+            .all(|(report, &passed)| report.is_some() || passed)
+    }
+
+    fn into_ranges(self) -> SynchronizedRanges {
+        let missing = self
+            .reports
+            .iter()
+            .enumerate()
+            .filter(|(_, report)| report.is_none())
+            .map(|(port, _)| port)
+            .collect();
+
+        let (reports, received) = self.reports.into_iter().flatten().unzip();
+
+        SynchronizedRanges {
+            seq_num: self.seq_num,
+            trigger_txts: self.trigger_txts,
+            reports,
+            missing,
+            received,
+        }
+    }
+}
+
+/// Synchronizer of the range reports of several serial ports
+pub struct Synchronizer {
+    options: SynchronizerOptions,
+    ports: usize,
+    /// Groups in order of their first report
+    pending: VecDeque<Group>,
+}
+
+impl Synchronizer {
+    pub fn new(ports: usize, options: SynchronizerOptions) -> Self {
+        Synchronizer {
+            options,
+            ports,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Number of groups waiting for reports
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Add the report received on a port, and return the groups ready
+    pub fn push(
+        &mut self,
+        port: usize,
+        report: RangeReport,
+        received: ReceiveTime,
+    ) -> Vec<SynchronizedRanges> {
+        let mut ready = self.expire(received);
+
+        // The port has moved past the earlier groups it did not report
+        for group in self.pending.iter_mut() {
+            if group.reports[port].is_none() && seq_after(report.seq_num, group.seq_num) {
+                group.passed[port] = true;
+            }
+        }
+
+        let group = self.pending.iter_mut().find(|group| {
+            group.trigger_txts == report.trigger_txts && group.reports[port].is_none()
+        });
+
+        match group {
+            Some(group) => group.reports[port] = Some((report, received)),
+            None => {
+                let mut reports = vec![None; self.ports];
+                reports[port] = Some((report, received));
+
+                self.pending.push_back(Group {
+                    seq_num: report.seq_num,
+                    trigger_txts: report.trigger_txts,
+                    first_arrival: received.rx_monotonic,
+                    reports,
+                    passed: vec![false; self.ports],
+                });
+            }
+        }
+
+        // Bound the number of pending groups
+        while self.pending.len() > self.options.max_pending {
+            ready.push(self.pending.pop_front().unwrap().into_ranges());
+        }
+
+        ready.extend(self.pop_complete());
+        ready
+    }
+
+    /// Return the groups whose window has elapsed, with the missing ports flagged
+    pub fn expire(&mut self, now: ReceiveTime) -> Vec<SynchronizedRanges> {
+        let mut ready = Vec::new();
+
+        while let Some(group) = self.pending.front() {
+            if now.rx_monotonic - group.first_arrival < self.options.window.as_secs_f64() {
+                break;
+            }
+            ready.push(self.pending.pop_front().unwrap().into_ranges());
+        }
+
+        ready.extend(self.pop_complete());
+        ready
+    }
+
+    /// Return the complete groups at the front, keeping the order of the triggers
+    fn pop_complete(&mut self) -> Vec<SynchronizedRanges> {
+        let mut ready = Vec::new();
+
+        while let Some(group) = self.pending.front() {
+            if !group.is_complete() {
+                break;
+            }
+            ready.push(self.pending.pop_front().unwrap().into_ranges());
+        }
+
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: f64) -> ReceiveTime {
+        ReceiveTime {
+            rx_monotonic: seconds,
+            rx_wall: 1.7e9 + seconds,
+        }
+    }
+
+    fn report(tag_addr: u16, seq_num: u8, trigger_txts: u64) -> RangeReport {
+        RangeReport {
+            tag_addr,
+            system_ts: trigger_txts,
+            seq_num,
+            trigger_txts,
+            ranges: [1.0; 8],
+        }
+    }
+
+    #[test]
+    fn test_complete_groups() {
+        let mut synchronizer = Synchronizer::new(2, SynchronizerOptions::default());
+
+        // Interleaved triggers
+        assert!(synchronizer.push(0, report(1, 1, 100), at(0.0)).is_empty());
+        assert!(synchronizer.push(0, report(1, 2, 200), at(0.0)).is_empty());
+        let ready = synchronizer.push(1, report(2, 1, 100), at(0.01));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].trigger_txts, 100);
+        assert_eq!(ready[0].reports.len(), 2);
+        assert_eq!(ready[0].received, vec![at(0.0), at(0.01)]);
+        assert!(ready[0].missing.is_empty());
+
+        let ready = synchronizer.push(1, report(2, 2, 200), at(0.0));
+        assert_eq!(ready[0].seq_num, 2);
+        assert_eq!(synchronizer.pending(), 0);
+    }
+
+    #[test]
+    fn test_partial_groups() {
+        let options = SynchronizerOptions {
+            window: Duration::from_millis(50),
+            max_pending: 4,
+        };
+        let mut synchronizer = Synchronizer::new(3, options);
+
+        // A silent port does not block the others
+        assert!(synchronizer.push(0, report(1, 1, 100), at(0.0)).is_empty());
+        assert!(synchronizer.push(1, report(2, 1, 100), at(0.0)).is_empty());
+        assert!(synchronizer.expire(at(0.01)).is_empty());
+        let ready = synchronizer.expire(at(0.06));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].reports.len(), 2);
+        assert_eq!(ready[0].missing, vec![2]);
+
+        // A port which reported a later sequence number missed the trigger,
+        // the group is emitted without waiting, across the wraparound
+        let now = at(0.1);
+        assert!(synchronizer.push(0, report(1, 255, 300), now).is_empty());
+        assert!(synchronizer.push(1, report(2, 255, 300), now).is_empty());
+        let ready = synchronizer.push(2, report(3, 0, 400), now);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].seq_num, 255);
+        assert_eq!(ready[0].missing, vec![2]);
+        assert_eq!(ready[0].ports(), vec![0, 1]);
+        assert_eq!(synchronizer.pending(), 1);
+
+        // The pending groups are bounded
+        for i in 0..10 {
+            synchronizer.push(0, report(1, i, 1000 + i as u64), now);
+        }
+        assert!(synchronizer.pending() <= 4);
+    }
+}
@@ -0,0 +1,467 @@
+// Tag tracking
+//
+// Kalman filter per tag with a constant velocity or constant acceleration motion
+// model, updated directly with the ranges to the anchors (tightly coupled EKF).
+// The tracks are started from a localized fix, and the ranges far from the
+// prediction are gated out.
+
+use std::collections::HashMap;
+
+use nalgebra::{DMatrix, DVector, Matrix3, Vector3};
+use serde::Serialize;
+
+use crate::{
+    configuration::Anchor,
+    optimization::{initial_covariance, Fix, Height},
+};
+
+/// Motion model of the tracks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MotionModel {
+    /// Position and velocity, driven by a white acceleration
+    ConstantVelocity,
+    /// Position, velocity and acceleration, driven by a white jerk
+    ConstantAcceleration,
+}
+
+impl MotionModel {
+    /// Number of state derivatives per axis
+    fn order(&self) -> usize {
+        match self {
+            MotionModel::ConstantVelocity => 2,
+            MotionModel::ConstantAcceleration => 3,
+        }
+    }
+}
+
+/// Options of the tracker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackerOptions {
+    pub model: MotionModel,
+    /// Spectral density of the white acceleration (or jerk) noise
+    pub process_noise: f64,
+    /// Standard deviation of the range noise, in meters
+    pub range_sigma: f64,
+    /// Initial standard deviation of the position when the fix covariance is not
+    /// finite, in meters
+    pub position_sigma: f64,
+    /// Initial standard deviation of the velocity (and acceleration), in m/s (m/s²)
+    pub velocity_sigma: f64,
+    /// Ranges with a larger normalized innovation squared are rejected
+    pub gate: f64,
+    /// A track is dropped when not updated for this time, in seconds
+    pub timeout: f64,
+    /// A track is restarted after this many updates with all the ranges rejected
+    pub max_rejected_updates: usize,
+}
+
+impl Default for TrackerOptions {
+    fn default() -> Self {
+        TrackerOptions {
+            model: MotionModel::ConstantVelocity,
+            process_noise: 0.5,
+            range_sigma: 0.1,
+            position_sigma: 1.0,
+            velocity_sigma: 1.0,
+            // 3 sigma
+            gate: 9.0,
+            timeout: 2.0,
+            max_rejected_updates: 5,
+        }
+    }
+}
+
+/// Estimate of a track, as published on `tracks`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackEstimate {
+    pub tag_addr: u16,
+    /// Time of the estimate, in seconds
+    pub time: f64,
+    pub position: [f64; 3],
+    pub velocity: [f64; 3],
+    /// Position covariance, in m²
+    pub covariance: [[f64; 3]; 3],
+    /// Ids of the anchors used in the update
+    pub ranges_used: Vec<usize>,
+    /// Ids of the anchors gated out
+    pub ranges_rejected: Vec<usize>,
+}
+
+/// Kalman filter of a single tag
+#[derive(Debug, Clone)]
+pub struct Track {
+    model: MotionModel,
+    /// Position, velocity (and acceleration), axis by axis derivatives
+    state: DVector<f64>,
+    covariance: DMatrix<f64>,
+    time: f64,
+    rejected_updates: usize,
+}
+
+impl Track {
+    /// Start a track from a localized position and its covariance
+    ///
+    /// A non-finite covariance is replaced by `options.position_sigma`.
+    pub fn new(
+        position: Vector3<f64>,
+        covariance: Matrix3<f64>,
+        time: f64,
+        options: &TrackerOptions,
+    ) -> Self {
+        let n = 3 * options.model.order();
+        let mut state = DVector::zeros(n);
+        state.fixed_rows_mut::<3>(0).copy_from(&position);
+
+        let mut initial = DMatrix::identity(n, n) * options.velocity_sigma.powi(2);
+        initial
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&initial_covariance(&covariance, options.position_sigma));
+
+        Track {
+            model: options.model,
+            state,
+            covariance: initial,
+            time,
+            rejected_updates: 0,
+        }
+    }
+
+    pub fn position(&self) -> Vector3<f64> {
+        self.state.fixed_rows::<3>(0).into()
+    }
+
+    pub fn velocity(&self) -> Vector3<f64> {
+        self.state.fixed_rows::<3>(3).into()
+    }
+
+    pub fn position_covariance(&self) -> Matrix3<f64> {
+        self.covariance.fixed_view::<3, 3>(0, 0).into()
+    }
+
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Propagate the state to `time`
+    pub fn predict(&mut self, time: f64, options: &TrackerOptions) {
+        let dt = time - self.time;
+        if dt <= 0.0 {
+            return;
+        }
+
+        let order = self.model.order();
+        let n = 3 * order;
+
+        // Per axis transition and process noise, for the derivatives 0..order
+        let mut f = DMatrix::<f64>::identity(order, order);
+        let mut q = DMatrix::<f64>::zeros(order, order);
+        for i in 0..order {
+            for j in i + 1..order {
+                f[(i, j)] = dt.powi((j - i) as i32) / factorial(j - i);
+            }
+        }
+        for i in 0..order {
+            for j in 0..order {
+                // Integral of the white noise on the highest derivative
+                let (a, b) = (order - 1 - i, order - 1 - j);
+                q[(i, j)] = options.process_noise * dt.powi((a + b + 1) as i32)
+                    / ((a + b + 1) as f64 * factorial(a) * factorial(b));
+            }
+        }
+
+        let mut transition = DMatrix::zeros(n, n);
+        let mut noise = DMatrix::zeros(n, n);
+        for axis in 0..3 {
+            for i in 0..order {
+                for j in 0..order {
+                    transition[(3 * i + axis, 3 * j + axis)] = f[(i, j)];
+                    noise[(3 * i + axis, 3 * j + axis)] = q[(i, j)];
+                }
+            }
+        }
+
+        self.state = &transition * &self.state;
+        self.covariance = &transition * &self.covariance * transition.transpose() + noise;
+        self.time = time;
+    }
+
+    /// Scalar measurement update, returns false if the measurement is gated out
+    fn update_scalar(
+        &mut self,
+        jacobian: &DVector<f64>,
+        innovation: f64,
+        variance: f64,
+        gate: f64,
+    ) -> bool {
+        let ph = &self.covariance * jacobian;
+        let s = jacobian.dot(&ph) + variance;
+        if innovation.powi(2) / s > gate {
+            return false;
+        }
+
+        let gain = ph / s;
+        self.state += &gain * innovation;
+        self.covariance -= &gain * gain.transpose() * s;
+        // Keep the covariance symmetric
+        self.covariance = (&self.covariance + self.covariance.transpose()) * 0.5;
+        true
+    }
+
+    /// Update with the ranges to the anchors, indexed by anchor id
+    ///
+    /// Returns the ids of the used and of the rejected anchors.
+    pub fn update_ranges(
+        &mut self,
+        anchors: &[Anchor],
+        ranges: &[f64],
+        options: &TrackerOptions,
+    ) -> (Vec<usize>, Vec<usize>) {
+        let n = self.state.len();
+        let mut used = Vec::new();
+        let mut rejected = Vec::new();
+
+        for anchor in anchors {
+            let Some(&range) = ranges.get(anchor.id) else {
+                continue;
+            };
+            if !range.is_normal() {
+                continue;
+            }
+
+            let diff = self.position() - anchor.position();
+            let predicted = diff.norm();
+            if predicted < 1e-9 {
+                continue;
+            }
+
+            let mut jacobian = DVector::zeros(n);
+            jacobian
+                .fixed_rows_mut::<3>(0)
+                .copy_from(&(diff / predicted));
+
+            if self.update_scalar(
+                &jacobian,
+                range - predicted,
+                options.range_sigma.powi(2),
+                options.gate,
+            ) {
+                used.push(anchor.id);
+            } else {
+                rejected.push(anchor.id);
+            }
+        }
+
+        if used.is_empty() && !rejected.is_empty() {
+            self.rejected_updates += 1;
+        } else {
+            self.rejected_updates = 0;
+        }
+
+        (used, rejected)
+    }
+
+    /// Apply a height constraint as a measurement of z
+    pub fn update_height(&mut self, height: Height) {
+        let (value, sigma) = match height {
+            Height::Free => return,
+            Height::Fixed(value) => (value, 1e-3),
+            Height::Prior { height, sigma } => (height, sigma),
+        };
+
+        let mut jacobian = DVector::zeros(self.state.len());
+        jacobian[2] = 1.0;
+        let innovation = value - self.state[2];
+        self.update_scalar(&jacobian, innovation, sigma.powi(2), f64::INFINITY);
+    }
+}
+
+fn factorial(n: usize) -> f64 {
+    (1..=n).product::<usize>() as f64
+}
+
+/// Tracks of all the tags
+pub struct Tracker {
+    options: TrackerOptions,
+    tracks: HashMap<u16, Track>,
+}
+
+impl Tracker {
+    pub fn new(options: TrackerOptions) -> Self {
+        Tracker {
+            options,
+            tracks: HashMap::new(),
+        }
+    }
+
+    pub fn track(&self, tag: u16) -> Option<&Track> {
+        self.tracks.get(&tag)
+    }
+
+    /// Update the track of a tag with its ranges at `time`, in seconds
+    ///
+    /// A track is started (or restarted, when it timed out, the time went backwards
+    /// or it kept rejecting all the ranges) from `fix`, the localized position of
+    /// the same ranges, if any. Returns the updated estimate.
+    pub fn update(
+        &mut self,
+        tag: u16,
+        time: f64,
+        anchors: &[Anchor],
+        ranges: &[f64],
+        height: Height,
+        fix: Option<&Fix>,
+    ) -> Option<TrackEstimate> {
+        let options = &self.options;
+
+        let stale = self.tracks.get(&tag).is_some_and(|track| {
+            time < track.time
+                || time - track.time > options.timeout
+                || track.rejected_updates >= options.max_rejected_updates
+        });
+        if stale {
+            self.tracks.remove(&tag);
+        }
+
+        let (used, rejected) = match self.tracks.get_mut(&tag) {
+            Some(track) => {
+                track.predict(time, options);
+                let result = track.update_ranges(anchors, ranges, options);
+                track.update_height(height);
+                result
+            }
+            None => {
+                let fix = fix?;
+                let track = Track::new(fix.solution.position, fix.covariance, time, options);
+                self.tracks.insert(tag, track);
+                (Vec::new(), Vec::new())
+            }
+        };
+
+        let track = &self.tracks[&tag];
+        Some(TrackEstimate {
+            tag_addr: tag,
+            time,
+            position: track.position().into(),
+            velocity: track.velocity().into(),
+            covariance: track.position_covariance().into(),
+            ranges_used: used,
+            ranges_rejected: rejected,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimization::fixtures::{ranges, site};
+
+    #[test]
+    fn test_track_constant_velocity() {
+        let site = site();
+        let velocity = Vector3::new(0.5, -0.2, 0.0);
+        let start = Vector3::new(2.0, 3.0, 1.0);
+
+        for model in [
+            MotionModel::ConstantVelocity,
+            MotionModel::ConstantAcceleration,
+        ] {
+            let options = TrackerOptions {
+                model,
+                process_noise: 0.05,
+                ..Default::default()
+            };
+            let mut track = Track::new(start, Matrix3::identity() * 0.01, 0.0, &options);
+
+            for k in 1..=100 {
+                let time = k as f64 * 0.1;
+                // Deterministic range noise
+                let noise = 0.02 * ((k % 5) as f64 - 2.0);
+                let ranges: Vec<f64> = ranges(&site, start + velocity * time)
+                    .iter()
+                    .map(|r| r + noise)
+                    .collect();
+
+                track.predict(time, &options);
+                let (used, rejected) = track.update_ranges(&site.anchors, &ranges, &options);
+                assert_eq!(used.len(), 5);
+                assert!(rejected.is_empty());
+            }
+
+            assert!((track.position() - (start + velocity * 10.0)).norm() < 0.05);
+            assert!((track.velocity() - velocity).norm() < 0.05);
+        }
+    }
+
+    #[test]
+    fn test_track_gating() {
+        let site = site();
+        let options = TrackerOptions::default();
+        let position = Vector3::new(2.0, 3.0, 1.0);
+        let mut track = Track::new(position, Matrix3::identity() * 0.01, 0.0, &options);
+
+        let mut ranges = ranges(&site, position);
+        ranges[3] += 2.0;
+        ranges[4] = f64::NAN;
+
+        track.predict(0.1, &options);
+        let (used, rejected) = track.update_ranges(&site.anchors, &ranges, &options);
+        assert_eq!(used, vec![0, 1, 2]);
+        assert_eq!(rejected, vec![3]);
+        assert!((track.position() - position).norm() < 0.05);
+    }
+
+    #[test]
+    fn test_track_infinite_covariance() {
+        let site = site();
+        let options = TrackerOptions::default();
+        let position = Vector3::new(2.0, 3.0, 1.0);
+        let mut track = Track::new(
+            position,
+            Matrix3::from_element(f64::INFINITY),
+            0.0,
+            &options,
+        );
+        assert_eq!(track.position_covariance(), Matrix3::identity());
+
+        let ranges = ranges(&site, position);
+        track.predict(0.1, &options);
+        let (used, rejected) = track.update_ranges(&site.anchors, &ranges, &options);
+        assert_eq!(used.len(), 5);
+        assert!(rejected.is_empty());
+        assert!(track.position().iter().all(|v| v.is_finite()));
+        assert!((track.position() - position).norm() < 0.05);
+    }
+
+    #[test]
+    fn test_tracker() {
+        let site = site();
+        let mut tracker = Tracker::new(TrackerOptions::default());
+        let position = Vector3::new(2.0, 3.0, 1.0);
+        let ranges = ranges(&site, position);
+
+        // No track without a fix
+        assert!(tracker
+            .update(1, 0.0, &site.anchors, &ranges, Height::Free, None)
+            .is_none());
+
+        let fix =
+            crate::optimization::localize_point(&site.anchors, &ranges, None, &Default::default())
+                .unwrap();
+        let estimate = tracker
+            .update(1, 0.0, &site.anchors, &ranges, Height::Free, Some(&fix))
+            .unwrap();
+        assert_eq!(estimate.tag_addr, 1);
+
+        let estimate = tracker
+            .update(1, 0.1, &site.anchors, &ranges, Height::Free, None)
+            .unwrap();
+        assert_eq!(estimate.ranges_used.len(), 5);
+        assert!((Vector3::from(estimate.position) - position).norm() < 1e-3);
+
+        // Timed out, restarted from the fix only
+        assert!(tracker
+            .update(1, 10.0, &site.anchors, &ranges, Height::Free, None)
+            .is_none());
+        assert!(tracker.track(1).is_none());
+    }
+}